@@ -1,67 +1,1448 @@
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader};
+use crate::deb822::{Paragraph, Paragraphs, ParseError};
+use crate::deb_file::Compression;
+use crate::deb_relation::{RelationError, Relations};
+use crate::deb_version::{DebVersion, VersionError};
+use crate::openpgp::{Keyring, SigningKey};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
 
-pub struct Package {
-    pub fields: HashMap<String, String>,
+#[derive(Debug)]
+pub enum RepoError {
+    /// A file could not be downloaded or opened.
+    Fetch { url: String, error: String },
+    /// A file was fetched but could not be parsed.
+    Parse { url: String, error: ParseError },
+    /// A file did not match what the Release file says about it.
+    Verification { url: String, error: String },
 }
 
-impl Package {
-    pub fn package_url(&self) -> String {
-        return format!(
-            "https://k51qzi5uqu5dg9vawh923wejqffxiu9bhqlze5f508msk0h7ylpac27fdgaskx.ipns.dweb.link/{}",
-            self.fields.get("Filename").expect("No 'Filename")
-        );
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepoError::Fetch { url, error } => write!(f, "Error fetching {}: {}", url, error),
+            RepoError::Parse { url, error } => write!(f, "Error parsing {}: {}", url, error),
+            RepoError::Verification { url, error } => {
+                write!(f, "Error verifying {}: {}", url, error)
+            }
+        }
     }
 }
 
-pub fn fetch_repo(arch: &str) -> HashMap<String, Package> {
-    let url = format!(
-        "https://termux.org/packages/dists/stable/main/binary-{}/Packages",
-        arch
-    );
+impl std::error::Error for RepoError {}
+
+/// An apt repository to fetch packages from, given by a `http://`,
+/// `https://` or `file://` base URL containing the `dists/` directory, and
+/// the suite, component and architecture to use in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepoSource {
+    pub url: String,
+    pub suite: String,
+    pub component: String,
+    pub arch: String,
+    /// A keyring file with the keys trusted to sign the Release file. If
+    /// set, an unsigned or wrongly signed repository is rejected.
+    pub signed_by: Option<PathBuf>,
+    /// The architectures the repository is used for, if restricted, such as
+    /// by `arch=` in sources.list. Architecture independent packages are
+    /// always used.
+    pub architectures: Option<Vec<String>>,
+    /// Where to keep downloaded files, if anywhere.
+    pub cache: Option<DownloadCache>,
+    pub download: DownloadOptions,
+}
 
-    match reqwest::blocking::get(&url) {
-        Ok(response) => {
-            let reader = BufReader::new(response);
-            parse_packages(reader)
+impl Default for RepoSource {
+    fn default() -> Self {
+        RepoSource {
+            url: "https://termux.org/packages".to_string(),
+            suite: "stable".to_string(),
+            component: "main".to_string(),
+            arch: "aarch64".to_string(),
+            signed_by: None,
+            architectures: None,
+            cache: None,
+            download: DownloadOptions::default(),
         }
-        Err(error) => panic!("Error fetching {}: {:?}", url, error),
     }
 }
 
-fn parse_packages(reader: impl BufRead) -> HashMap<String, Package> {
-    let mut result: HashMap<String, Package> = HashMap::new();
-    let mut current_package: HashMap<String, String> = HashMap::new();
-    for line in reader.lines() {
-        let line = line.expect("Failed reading packages");
-        if line.is_empty() {
-            let package_name = current_package["Package"].clone();
-            let package = Package {
-                fields: current_package,
-            };
-            result.insert(package_name, package);
-            current_package = HashMap::new();
-        } else if line.starts_with(' ') {
-            // Ignore multiline (probably description) field for now.
+impl RepoSource {
+    /// Read a repository configuration file: a single deb822 paragraph with
+    /// optional `URL`, `Suite`, `Component`, `Architecture` and `Signed-By`
+    /// fields, where missing fields keep their default values.
+    pub fn read_config(path: &Path) -> Result<RepoSource, RepoError> {
+        let url = path.display().to_string();
+        let mut contents = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|error| RepoError::Fetch {
+                url: url.clone(),
+                error: error.to_string(),
+            })?;
+        let config: Paragraph = contents
+            .parse()
+            .map_err(|error| RepoError::Parse { url, error })?;
+
+        let mut source = RepoSource::default();
+        for (field, value) in &mut [
+            ("URL", &mut source.url),
+            ("Suite", &mut source.suite),
+            ("Component", &mut source.component),
+            ("Architecture", &mut source.arch),
+        ] {
+            if let Some(config_value) = config.get(field) {
+                **value = config_value.to_string();
+            }
+        }
+        source.signed_by = config.get("Signed-By").map(PathBuf::from);
+        Ok(source)
+    }
+
+    /// Read an apt sources file, either a deb822 `.sources` file or a
+    /// sources.list file with one-line entries. See `parse_sources_list` and
+    /// `parse_deb822_sources`.
+    pub fn read_sources(path: &Path) -> Result<Vec<RepoSource>, RepoError> {
+        let url = path.display().to_string();
+        let contents = fs::read_to_string(path).map_err(|error| RepoError::Fetch {
+            url: url.clone(),
+            error: error.to_string(),
+        })?;
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "sources")
+        {
+            parse_deb822_sources(&contents)
         } else {
-            let mut parts = line.splitn(2, ':');
-            let key = parts.next().unwrap().to_string();
-            let value = parts.next().unwrap().to_string().trim().to_string();
-            current_package.insert(key, value);
+            parse_sources_list(&contents)
         }
+        .map_err(|error| RepoError::Parse { url, error })
     }
 
-    if !current_package.is_empty() {
-        let package_name = current_package["Package"].clone();
-        let package = Package {
-            fields: current_package,
+    /// If the repository is used for an architecture.
+    pub fn supports_arch(&self, arch: &str) -> bool {
+        arch == "all"
+            || self
+                .architectures
+                .as_ref()
+                .is_none_or(|architectures| architectures.iter().any(|a| a == arch))
+    }
+
+    pub fn with_arch(&self, arch: &str) -> RepoSource {
+        RepoSource {
+            arch: arch.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// The URL of a file given relative to the repository root, such as the
+    /// `Filename` of a package.
+    pub fn file_url(&self, path: &str) -> String {
+        format!("{}/{}", self.url.trim_end_matches('/'), path)
+    }
+
+    /// The URL of a file in the `dists/<suite>/` directory.
+    pub fn dist_url(&self, path: &str) -> String {
+        self.file_url(&format!("dists/{}/{}", self.suite, path))
+    }
+
+    /// The path of the Packages index relative to the `dists/<suite>/`
+    /// directory, as listed in the Release file.
+    pub fn packages_path(&self) -> String {
+        format!("{}/binary-{}/Packages", self.component, self.arch)
+    }
+
+    pub fn packages_url(&self) -> String {
+        self.dist_url(&self.packages_path())
+    }
+
+    /// Fetch a file which is not content addressed, such as InRelease,
+    /// through the download cache if there is one.
+    fn fetch_bytes(&self, url: &str) -> Result<Vec<u8>, RepoError> {
+        match &self.cache {
+            Some(cache) => cache.fetch_list(url, &self.download),
+            None => fetch_bytes(url, &self.download),
+        }
+    }
+}
+
+/// The sources of one entry in an apt sources file, one for each URL, suite
+/// and component, in that order.
+fn entry_sources(
+    urls: &[&str],
+    suites: &[&str],
+    components: &[&str],
+    architectures: Option<Vec<String>>,
+    signed_by: Option<&str>,
+) -> Result<Vec<RepoSource>, String> {
+    if urls.is_empty() || suites.is_empty() {
+        return Err("missing URL or suite".to_string());
+    }
+    if components.is_empty() {
+        return Err("flat repositories without components are not supported".to_string());
+    }
+    let signed_by = match signed_by.map(str::trim) {
+        Some(keys) if keys.contains("BEGIN PGP") => {
+            return Err("inline Signed-By keys are not supported".to_string())
+        }
+        Some(keys) if keys.contains(|c: char| c == ',' || c.is_whitespace()) => {
+            return Err("only a single Signed-By keyring is supported".to_string())
+        }
+        keys => keys.map(PathBuf::from),
+    };
+    let default = RepoSource::default();
+    let arch = architectures
+        .as_ref()
+        .and_then(|architectures| architectures.first())
+        .unwrap_or(&default.arch);
+    let mut sources = Vec::new();
+    for url in urls {
+        for suite in suites {
+            for component in components {
+                sources.push(RepoSource {
+                    url: url.to_string(),
+                    suite: suite.to_string(),
+                    component: component.to_string(),
+                    arch: arch.clone(),
+                    signed_by: signed_by.clone(),
+                    architectures: architectures.clone(),
+                    ..RepoSource::default()
+                });
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// Parse sources.list entries such as
+/// `deb [arch=aarch64 signed-by=/etc/apt/keyring.gpg] https://example.com stable main x11`,
+/// with one source per component. `deb-src` entries are skipped, as are
+/// options other than `arch` and `signed-by`.
+pub fn parse_sources_list(text: &str) -> Result<Vec<RepoSource>, ParseError> {
+    let mut sources = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let syntax_error = |message: String| ParseError::Syntax {
+            line: index + 1,
+            message,
         };
-        result.insert(package_name, package);
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, mut rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match kind {
+            "deb" => {}
+            "deb-src" => continue,
+            _ => return Err(syntax_error(format!("unknown entry type '{}'", kind))),
+        }
+
+        let mut architectures = None;
+        let mut signed_by = None;
+        if let Some(options) = rest.trim_start().strip_prefix('[') {
+            let (options, after_options) = options
+                .split_once(']')
+                .ok_or_else(|| syntax_error("unterminated options".to_string()))?;
+            for option in options.split_whitespace() {
+                match option.split_once('=') {
+                    Some(("arch", value)) => {
+                        architectures = Some(value.split(',').map(String::from).collect())
+                    }
+                    Some(("signed-by", value)) => signed_by = Some(value),
+                    Some(_) => {}
+                    None => return Err(syntax_error(format!("invalid option '{}'", option))),
+                }
+            }
+            rest = after_options;
+        }
+
+        let words: Vec<_> = rest.split_whitespace().collect();
+        if words.len() < 2 {
+            return Err(syntax_error("missing URL or suite".to_string()));
+        }
+        sources.extend(
+            entry_sources(
+                &words[..1],
+                &words[1..2],
+                &words[2..],
+                architectures,
+                signed_by,
+            )
+            .map_err(syntax_error)?,
+        );
+    }
+    Ok(sources)
+}
+
+/// Parse a deb822 `.sources` file, with paragraphs containing `Types`,
+/// `URIs`, `Suites`, `Components` and optionally `Architectures`,
+/// `Signed-By` and `Enabled` fields. Paragraphs without the `deb` type or
+/// with `Enabled: no` are skipped.
+pub fn parse_deb822_sources(text: &str) -> Result<Vec<RepoSource>, ParseError> {
+    let mut sources = Vec::new();
+    let mut paragraphs = Paragraphs::new(text.as_bytes());
+    while let Some(paragraph) = paragraphs.next() {
+        let paragraph = paragraph?;
+        let line = paragraphs.paragraph_line();
+        let words = |field: &str| -> Vec<&str> {
+            paragraph
+                .get(field)
+                .map_or_else(Vec::new, |value| value.split_whitespace().collect())
+        };
+        let enabled = paragraph
+            .get("Enabled")
+            .is_none_or(|value| value.trim() != "no");
+        if !enabled || !words("Types").contains(&"deb") {
+            continue;
+        }
+        let architectures = paragraph
+            .get("Architectures")
+            .map(|value| value.split_whitespace().map(String::from).collect());
+        sources.extend(
+            entry_sources(
+                &words("URIs"),
+                &words("Suites"),
+                &words("Components"),
+                architectures,
+                paragraph.get("Signed-By"),
+            )
+            .map_err(|message| ParseError::Syntax { line, message })?,
+        );
+    }
+    Ok(sources)
+}
+
+/// How files are downloaded over HTTP.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DownloadOptions {
+    /// How many times a failed request is retried. The delay before each
+    /// retry doubles, starting with `retry_delay`.
+    pub retries: u32,
+    pub retry_delay: Duration,
+    /// The time limit for each request, including reading the response.
+    pub timeout: Duration,
+    /// A proxy URL to use for all requests, instead of the one given by the
+    /// `http_proxy` and `https_proxy` environment variables.
+    pub proxy: Option<String>,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        DownloadOptions {
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            timeout: Duration::from_secs(300),
+            proxy: None,
+        }
+    }
+}
+
+/// The HTTP client for some download options, shared by all requests using
+/// them so that connections are reused.
+fn http_client(options: &DownloadOptions) -> Result<reqwest::blocking::Client, String> {
+    type ClientKey = (Duration, Option<String>);
+    static CLIENTS: OnceLock<Mutex<HashMap<ClientKey, reqwest::blocking::Client>>> =
+        OnceLock::new();
+    let mut clients = CLIENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    let key = (options.timeout, options.proxy.clone());
+    if let Some(client) = clients.get(&key) {
+        return Ok(client.clone());
+    }
+    let mut builder = reqwest::blocking::Client::builder()
+        .timeout(options.timeout)
+        .connect_timeout(options.timeout.min(Duration::from_secs(30)));
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(|e| e.to_string())?);
+    }
+    let client = builder.build().map_err(|e| e.to_string())?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+/// A directory of downloaded files, which can be shared between threads and
+/// runs. Package and index files are stored as `sha256/<hash>` using the
+/// SHA256 from the index, and Release files as `lists/<url>`, like apt does.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadCache {
+    pub dir: PathBuf,
+    /// Only use files already in the cache, never downloading anything.
+    pub offline: bool,
+}
+
+/// Lock a path in the cache while it is checked and downloaded, so that a
+/// file needed by several threads is only downloaded once.
+fn lock_cache_path(path: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+    let mut locks = LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    Arc::clone(locks.entry(path.to_path_buf()).or_default())
+}
+
+/// Write a file by writing and renaming a temporary file next to it, so that
+/// an interrupted write never leaves a partial file behind.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<(), RepoError>,
+) -> Result<(), RepoError> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let temp_path = path.with_extension(format!(
+        "partial-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let io_error = |error: io::Error| RepoError::Fetch {
+        url: path.display().to_string(),
+        error: error.to_string(),
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| File::create(&temp_path))
+        .map_err(io_error)
+        .and_then(|mut file| write(&mut file))
+        .and_then(|()| fs::rename(&temp_path, path).map_err(io_error));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+impl DownloadCache {
+    fn offline_error(&self, url: &str) -> RepoError {
+        RepoError::Fetch {
+            url: url.to_string(),
+            error: format!("not in the download cache {} (offline)", self.dir.display()),
+        }
+    }
+
+    /// Open the file with the given SHA256 from the cache, downloading it
+    /// from `url` if it is missing. A downloaded file is only added to the
+    /// cache if it has the expected size and hash.
+    pub fn open(
+        &self,
+        url: &str,
+        size: u64,
+        sha256: &str,
+        options: &DownloadOptions,
+    ) -> Result<File, RepoError> {
+        let path = self.path(url, size, sha256, options)?;
+        File::open(&path).map_err(|error| RepoError::Fetch {
+            url: path.display().to_string(),
+            error: error.to_string(),
+        })
+    }
+
+    /// The path of the file with the given SHA256 in the cache, downloading
+    /// it as with `open` if it is missing.
+    pub fn path(
+        &self,
+        url: &str,
+        size: u64,
+        sha256: &str,
+        options: &DownloadOptions,
+    ) -> Result<PathBuf, RepoError> {
+        let sha256 = checked_sha256(url, sha256)?;
+        let path = self.dir.join("sha256").join(&sha256);
+        let lock = lock_cache_path(&path);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        if !path.exists() {
+            if self.offline {
+                return Err(self.offline_error(url));
+            }
+            write_atomically(&path, |file| {
+                download_into(url, size, &sha256, options, file)
+            })?;
+        }
+        Ok(path)
+    }
+
+    /// Read the file with the given SHA256, as with `open`.
+    fn fetch(
+        &self,
+        url: &str,
+        size: u64,
+        sha256: &str,
+        options: &DownloadOptions,
+    ) -> Result<Vec<u8>, RepoError> {
+        let mut contents = Vec::new();
+        self.open(url, size, sha256, options)?
+            .read_to_end(&mut contents)
+            .map_err(|error| RepoError::Fetch {
+                url: url.to_string(),
+                error: error.to_string(),
+            })?;
+        Ok(contents)
+    }
+
+    /// Fetch a file which is not content addressed, keeping the last
+    /// downloaded version for use when offline.
+    fn fetch_list(&self, url: &str, options: &DownloadOptions) -> Result<Vec<u8>, RepoError> {
+        let name: String = url
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "-.".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = self.dir.join("lists").join(name);
+        if self.offline {
+            return fs::read(&path).map_err(|error| match error.kind() {
+                io::ErrorKind::NotFound => self.offline_error(url),
+                _ => RepoError::Fetch {
+                    url: path.display().to_string(),
+                    error: error.to_string(),
+                },
+            });
+        }
+        let contents = fetch_bytes(url, options)?;
+        let lock = lock_cache_path(&path);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        write_atomically(&path, |file| {
+            file.write_all(&contents).map_err(|error| RepoError::Fetch {
+                url: path.display().to_string(),
+                error: error.to_string(),
+            })
+        })?;
+        Ok(contents)
+    }
+}
+
+/// Call `attempt` until it succeeds, fails with an error which is not
+/// worth retrying, or the retries are used up. Errors are paired with whether
+/// they are worth retrying.
+fn with_retries<T>(
+    options: &DownloadOptions,
+    mut attempt: impl FnMut() -> Result<T, (RepoError, bool)>,
+) -> Result<T, RepoError> {
+    let mut delay = options.retry_delay;
+    for _ in 0..options.retries {
+        match attempt() {
+            Err((_, true)) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result.map_err(|(error, _)| error),
+        }
+    }
+    attempt().map_err(|(error, _)| error)
+}
+
+/// Open a URL once. Failures other than client errors like 404 are worth
+/// retrying for http URLs.
+fn try_open_url(
+    url: &str,
+    options: &DownloadOptions,
+) -> Result<Box<dyn Read + Send>, (RepoError, bool)> {
+    let fetch_error = |error: String| RepoError::Fetch {
+        url: url.to_string(),
+        error,
+    };
+    if let Some(path) = url.strip_prefix("file://") {
+        let file = File::open(path).map_err(|error| (fetch_error(error.to_string()), false))?;
+        Ok(Box::new(file))
+    } else {
+        let client = http_client(options).map_err(|error| (fetch_error(error), false))?;
+        let response = client
+            .get(url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| {
+                let retry = error.status().is_none_or(|status| {
+                    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                });
+                (fetch_error(error.to_string()), retry)
+            })?;
+        Ok(Box::new(response))
+    }
+}
+
+/// Open a `http://`, `https://` or `file://` URL for reading, retrying if
+/// the request fails.
+pub fn open_url(url: &str, options: &DownloadOptions) -> Result<Box<dyn Read + Send>, RepoError> {
+    with_retries(options, || try_open_url(url, options))
+}
+
+fn fetch_bytes(url: &str, options: &DownloadOptions) -> Result<Vec<u8>, RepoError> {
+    with_retries(options, || {
+        let mut contents = Vec::new();
+        try_open_url(url, options)?
+            .read_to_end(&mut contents)
+            .map_err(|error| {
+                let error = RepoError::Fetch {
+                    url: url.to_string(),
+                    error: error.to_string(),
+                };
+                (error, true)
+            })?;
+        Ok(contents)
+    })
+}
+
+/// Download a file and check its size and hash, retrying if the download
+/// fails but not if it has the wrong contents.
+fn download(
+    url: &str,
+    size: u64,
+    sha256: &str,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>, RepoError> {
+    with_retries(options, || {
+        let mut reader = VerifyingReader::new(try_open_url(url, options)?, url, size, sha256);
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents).map_err(|error| {
+            let error = reader.repo_error(error);
+            let retry = matches!(error, RepoError::Fetch { .. });
+            (error, retry)
+        })?;
+        Ok(contents)
+    })
+}
+
+/// Download a file into `file` like `download`, streaming it to disk
+/// instead of keeping it in memory. The file is truncated before each try.
+fn download_into(
+    url: &str,
+    size: u64,
+    sha256: &str,
+    options: &DownloadOptions,
+    file: &mut File,
+) -> Result<(), RepoError> {
+    let local_error = |error: io::Error| {
+        let error = RepoError::Fetch {
+            url: url.to_string(),
+            error: format!("writing download: {}", error),
+        };
+        (error, false)
+    };
+    with_retries(options, || {
+        file.set_len(0)
+            .and_then(|()| file.rewind())
+            .map_err(local_error)?;
+        let mut reader = VerifyingReader::new(try_open_url(url, options)?, url, size, sha256);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buffer).map_err(|error| {
+                let error = reader.repo_error(error);
+                let retry = matches!(error, RepoError::Fetch { .. });
+                (error, retry)
+            })?;
+            if read == 0 {
+                return Ok(());
+            }
+            file.write_all(&buffer[..read]).map_err(local_error)?;
+        }
+    })
+}
+
+/// Check that a SHA256 from an index is a hex string, so that it can be used
+/// in a file name.
+fn checked_sha256(url: &str, sha256: &str) -> Result<String, RepoError> {
+    let sha256 = sha256.to_ascii_lowercase();
+    if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(RepoError::Verification {
+            url: url.to_string(),
+            error: format!("invalid SHA256 '{}'", sha256),
+        });
+    }
+    Ok(sha256)
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    to_hex(&<sha1::Sha1 as sha1::Digest>::digest(data))
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// A reader which hashes the data read through it while streaming, and
+/// fails if it turns out not to have the expected size and SHA256 hash.
+///
+/// The check is done when reaching the end of the data, so readers which
+/// may stop early need to call `finish()` to read and check the rest.
+pub struct VerifyingReader<R: Read> {
+    inner: R,
+    url: String,
+    expected_size: u64,
+    expected_sha256: String,
+    size: u64,
+    hasher: Sha256,
+}
+
+impl<R: Read> VerifyingReader<R> {
+    pub fn new(inner: R, url: &str, expected_size: u64, expected_sha256: &str) -> Self {
+        VerifyingReader {
+            inner,
+            url: url.to_string(),
+            expected_size,
+            expected_sha256: expected_sha256.to_ascii_lowercase(),
+            size: 0,
+            hasher: Sha256::new(),
+        }
+    }
+
+    fn verification_error(&self, error: String) -> RepoError {
+        RepoError::Verification {
+            url: self.url.clone(),
+            error,
+        }
+    }
+
+    fn check(&self) -> Result<(), RepoError> {
+        if self.size != self.expected_size {
+            return Err(self.verification_error(format!(
+                "size is {}, expected {}",
+                self.size, self.expected_size
+            )));
+        }
+        let actual_sha256 = to_hex(&self.hasher.clone().finalize());
+        if actual_sha256 != self.expected_sha256 {
+            return Err(self.verification_error(format!(
+                "SHA256 is {}, expected {}",
+                actual_sha256, self.expected_sha256
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read the rest of the data and check its size and hash.
+    pub fn finish(mut self) -> Result<(), RepoError> {
+        io::copy(&mut self, &mut io::sink()).map_err(|error| self.repo_error(error))?;
+        Ok(())
+    }
+
+    /// Turn an error from reading into the verification error it wraps, or
+    /// a fetch error.
+    pub fn repo_error(&self, error: io::Error) -> RepoError {
+        if error.get_ref().is_some_and(|e| e.is::<RepoError>()) {
+            *error.into_inner().unwrap().downcast::<RepoError>().unwrap()
+        } else {
+            self.fetch_error(error.to_string())
+        }
+    }
+
+    fn fetch_error(&self, error: String) -> RepoError {
+        RepoError::Fetch {
+            url: self.url.clone(),
+            error,
+        }
+    }
+}
+
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.size += n as u64;
+        self.hasher.update(&buf[..n]);
+        if self.size > self.expected_size || (n == 0 && !buf.is_empty()) {
+            self.check()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(n)
     }
+}
+
+/// The size and hash of an index file as listed in a Release file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReleaseFile {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// A parsed Release file, or the signed contents of an InRelease file.
+#[derive(Clone, Debug)]
+pub struct Release {
+    pub fields: Paragraph,
+    /// The files in the `SHA256` field, by path relative to `dists/<suite>/`.
+    pub files: HashMap<String, ReleaseFile>,
+}
+
+impl FromStr for Release {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Paragraph = s.parse()?;
+        // The paragraph starts after any empty lines.
+        let first_line = 1 + s.lines().take_while(|line| line.trim().is_empty()).count();
+        let mut files = HashMap::new();
+        for (offset, line) in fields.get_lines_with_offsets("SHA256") {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let size = match parts[..] {
+                [_, size, _] => size.parse().ok(),
+                _ => None,
+            };
+            let size = size.ok_or_else(|| ParseError::Syntax {
+                line: first_line + offset,
+                message: format!("invalid SHA256 line '{}'", line),
+            })?;
+            files.insert(
+                parts[2].to_string(),
+                ReleaseFile {
+                    size,
+                    sha256: parts[0].to_ascii_lowercase(),
+                },
+            );
+        }
+        Ok(Release { fields, files })
+    }
+}
+
+/// Extract the signed text of a clearsigned OpenPGP message such as
+/// InRelease, removing the dash-escaping of lines.
+fn clearsigned_text(message: &str) -> Option<String> {
+    let mut lines = message.lines();
+    if lines.next()?.trim_end() != "-----BEGIN PGP SIGNED MESSAGE-----" {
+        return None;
+    }
+    // Skip armor headers such as "Hash: SHA512", which end with an empty line.
+    for line in &mut lines {
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+    let mut text = String::new();
+    for line in lines {
+        if line.trim_end() == "-----BEGIN PGP SIGNATURE-----" {
+            return Some(text);
+        }
+        text.push_str(line.strip_prefix("- ").unwrap_or(line));
+        text.push('\n');
+    }
+    None
+}
+
+impl Release {
+    pub fn from_clearsigned(message: &str) -> Result<Release, ParseError> {
+        clearsigned_text(message)
+            .ok_or_else(|| ParseError::Syntax {
+                line: 1,
+                message: "not a clearsigned message".to_string(),
+            })?
+            .parse()
+    }
+
+    /// If index files can be fetched by their hash, see `by_hash_path`.
+    pub fn acquire_by_hash(&self) -> bool {
+        self.fields.get("Acquire-By-Hash") == Some("yes")
+    }
+
+    /// Check that the contents of an index file match its size and hash in
+    /// this Release file.
+    pub fn verify_file(&self, path: &str, contents: &[u8]) -> Result<(), String> {
+        let expected = self
+            .files
+            .get(path)
+            .ok_or_else(|| format!("{} is not listed in the Release file", path))?;
+        if contents.len() as u64 != expected.size {
+            return Err(format!(
+                "size is {}, expected {}",
+                contents.len(),
+                expected.size
+            ));
+        }
+        let actual_sha256 = sha256_hex(contents);
+        if actual_sha256 != expected.sha256 {
+            return Err(format!(
+                "SHA256 is {}, expected {}",
+                actual_sha256, expected.sha256
+            ));
+        }
+        Ok(())
+    }
+}
 
+/// The fields to put in a generated Release file, besides the lists of
+/// architectures, components and index files.
+pub struct ReleaseOptions {
+    pub origin: Option<String>,
+    pub suite: String,
+    /// Defaults to the suite.
+    pub codename: Option<String>,
+    /// How long clients should consider the Release file valid, if limited.
+    pub valid_for: Option<chrono::Duration>,
+    /// Also publish the index files under their hashes, and set
+    /// `Acquire-By-Hash: yes`. See `by_hash_path`.
+    pub by_hash: bool,
+}
+
+/// The path of the copy of an index file named by its SHA256 hash, such as
+/// `main/binary-arm/by-hash/SHA256/<digest>` for `main/binary-arm/Packages.xz`.
+/// Unlike the file itself, such a copy never changes, so clients which
+/// fetched a Release file can still fetch the matching index while the
+/// repository is updated.
+pub fn by_hash_path(path: &str, sha256: &str) -> String {
+    match path.rsplit_once('/') {
+        Some((dir, _)) => format!("{}/by-hash/SHA256/{}", dir, sha256),
+        None => format!("by-hash/SHA256/{}", sha256),
+    }
+}
+
+/// Write the by-hash copies of index files in a suite directory, removing
+/// older copies except for those listed in the previous Release file, which
+/// clients may still be fetching.
+fn write_by_hash_copies(suite_dir: &Path, files: &[(String, Vec<u8>)]) -> io::Result<()> {
+    let previous_release = fs::read_to_string(suite_dir.join("Release"))
+        .ok()
+        .and_then(|text| text.parse::<Release>().ok());
+    let mut keep: BTreeSet<PathBuf> = previous_release
+        .iter()
+        .flat_map(|release| &release.files)
+        .map(|(path, file)| suite_dir.join(by_hash_path(path, &file.sha256)))
+        .collect();
+    for (path, contents) in files {
+        let by_hash_path = suite_dir.join(by_hash_path(path, &sha256_hex(contents)));
+        if !by_hash_path.exists() {
+            let dir = by_hash_path.parent().expect("By-hash path has a directory");
+            fs::create_dir_all(dir)?;
+            replace_file(&by_hash_path, contents)?;
+        }
+        keep.insert(by_hash_path);
+    }
+
+    let by_hash_dirs: BTreeSet<_> = keep
+        .iter()
+        .filter_map(|path| path.parent())
+        .filter(|dir| dir.is_dir())
+        .collect();
+    for dir in by_hash_dirs {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if !keep.contains(&path) {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Replace a file by renaming a temporary file into place, so that clients
+/// never see a partially written file.
+pub(crate) fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+    let result = File::create(&temp_path)
+        .and_then(|mut file| file.write_all(contents))
+        .and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
     result
 }
 
+/// Format a time like in Release files, such as `Sat, 17 Oct 2026 04:50:00 UTC`.
+fn format_release_date(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S UTC").to_string()
+}
+
+/// Write `<repo_dir>/dists/<suite>/Release`, listing the hashes of all index
+/// files in that directory. If a signing key is given, InRelease and
+/// Release.gpg are written as well, and otherwise removed if present.
+pub fn write_release(
+    repo_dir: &Path,
+    options: &ReleaseOptions,
+    signing_key: Option<&SigningKey>,
+) -> io::Result<Paragraph> {
+    let suite_dir = repo_dir.join("dists").join(&options.suite);
+    let mut files = Vec::new();
+    let mut architectures = BTreeSet::new();
+    let mut components = BTreeSet::new();
+    for entry in walkdir::WalkDir::new(&suite_dir)
+        .min_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.file_name() != "by-hash")
+    {
+        let entry = entry?;
+        let path = entry
+            .path()
+            .strip_prefix(&suite_dir)
+            .expect("Walked path is inside the suite")
+            .to_string_lossy()
+            .into_owned();
+        if entry.file_type().is_dir() {
+            if let Some((component, arch)) = path.split_once("/binary-") {
+                components.insert(component.to_string());
+                architectures.insert(arch.to_string());
+            }
+        } else if !["Release", "InRelease", "Release.gpg"].contains(&path.as_str()) {
+            files.push((path, fs::read(entry.path())?));
+        }
+    }
+
+    let now = chrono::Utc::now();
+    let mut release = Paragraph::new();
+    if let Some(origin) = &options.origin {
+        release.set("Origin", origin);
+    }
+    release.set("Suite", &options.suite);
+    release.set(
+        "Codename",
+        options.codename.as_ref().unwrap_or(&options.suite),
+    );
+    release.set("Date", &format_release_date(now));
+    if let Some(valid_for) = options.valid_for {
+        release.set("Valid-Until", &format_release_date(now + valid_for));
+    }
+    let join = |names: &BTreeSet<String>| names.iter().cloned().collect::<Vec<_>>().join(" ");
+    release.set("Architectures", &join(&architectures));
+    release.set("Components", &join(&components));
+    if options.by_hash {
+        release.set("Acquire-By-Hash", "yes");
+    }
+    let md5_hex: fn(&[u8]) -> String = |data| format!("{:x}", md5::compute(data));
+    let hash_functions = [
+        ("MD5Sum", md5_hex),
+        ("SHA1", sha1_hex),
+        ("SHA256", sha256_hex),
+    ];
+    for (field, hash_function) in &hash_functions {
+        let mut value = String::new();
+        for (path, contents) in &files {
+            value += &format!(
+                "\n {} {:>16} {}",
+                hash_function(contents),
+                contents.len(),
+                path
+            );
+        }
+        release.set(field, &value);
+    }
+
+    // The by-hash copies have to exist before the Release file listing them.
+    if options.by_hash {
+        write_by_hash_copies(&suite_dir, &files)?;
+    }
+    let release_text = release.to_string();
+    if let Some(signing_key) = signing_key {
+        // Sign before replacing any file, so that they change together.
+        let in_release = signing_key
+            .sign_clearsigned(&release_text)
+            .map_err(io::Error::other)?;
+        let signature = signing_key
+            .sign_detached(release_text.as_bytes())
+            .map_err(io::Error::other)?;
+        replace_file(&suite_dir.join("InRelease"), in_release.as_bytes())?;
+        replace_file(&suite_dir.join("Release"), release_text.as_bytes())?;
+        replace_file(&suite_dir.join("Release.gpg"), signature.as_bytes())?;
+    } else {
+        replace_file(&suite_dir.join("Release"), release_text.as_bytes())?;
+        // apt prefers InRelease, so signatures of an earlier Release must go.
+        for name in &["InRelease", "Release.gpg"] {
+            match fs::remove_file(suite_dir.join(name)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+    }
+    Ok(release)
+}
+
+/// Fetch the InRelease file of a repository, falling back to Release if
+/// there is none. If the source has a keyring, the signature of InRelease or
+/// the detached Release.gpg signature of Release has to be valid.
+pub fn fetch_release(source: &RepoSource) -> Result<Release, RepoError> {
+    let keyring = match &source.signed_by {
+        Some(path) => Some(Keyring::read(path).map_err(|e| RepoError::Verification {
+            url: path.display().to_string(),
+            error: e.to_string(),
+        })?),
+        None => None,
+    };
+
+    let in_release_url = source.dist_url("InRelease");
+    let (url, text) = match source.fetch_bytes(&in_release_url) {
+        Ok(contents) => {
+            let message = String::from_utf8_lossy(&contents);
+            let text =
+                match &keyring {
+                    Some(keyring) => keyring.verify_clearsigned(&message).map_err(|e| {
+                        RepoError::Verification {
+                            url: in_release_url.clone(),
+                            error: e.to_string(),
+                        }
+                    })?,
+                    None => clearsigned_text(&message).ok_or_else(|| RepoError::Parse {
+                        url: in_release_url.clone(),
+                        error: ParseError::Syntax {
+                            line: 1,
+                            message: "not a clearsigned message".to_string(),
+                        },
+                    })?,
+                };
+            (in_release_url, text)
+        }
+        Err(_) => {
+            let url = source.dist_url("Release");
+            let contents = source.fetch_bytes(&url)?;
+            if let Some(keyring) = &keyring {
+                let signature_url = source.dist_url("Release.gpg");
+                let signature = source.fetch_bytes(&signature_url)?;
+                keyring
+                    .verify_detached(&contents, &signature)
+                    .map_err(|e| RepoError::Verification {
+                        url: signature_url,
+                        error: e.to_string(),
+                    })?;
+            }
+            (url, String::from_utf8_lossy(&contents).into_owned())
+        }
+    };
+    text.parse()
+        .map_err(|error| RepoError::Parse { url, error })
+}
+
+#[derive(Clone)]
+pub struct Package {
+    pub fields: Paragraph,
+    /// The parsed `Version` field.
+    pub version: DebVersion,
+    /// The repository this package was listed in.
+    pub source: Arc<RepoSource>,
+}
+
+impl Package {
+    pub fn package_url(&self) -> String {
+        self.source
+            .file_url(self.fields.get("Filename").expect("No 'Filename"))
+    }
+
+    /// Parse a relationship field such as Depends, which is empty if the
+    /// field is missing.
+    pub fn relations(&self, field: &str) -> Result<Relations, RelationError> {
+        self.fields.get(field).unwrap_or("").parse()
+    }
+
+    /// The URL of the deb file of this package, and its size and SHA256 from
+    /// the index.
+    fn location(&self) -> Result<(String, u64, &str), RepoError> {
+        let url = self.package_url();
+        let size = self.fields.get("Size").and_then(|size| size.parse().ok());
+        match (size, self.fields.get("SHA256")) {
+            (Some(size), Some(sha256)) => Ok((url, size, sha256)),
+            _ => Err(RepoError::Verification {
+                url,
+                error: "no Size and SHA256 in the package index".to_string(),
+            }),
+        }
+    }
+
+    /// Start downloading the deb file of this package, or open it from the
+    /// download cache, checking it against the `Size` and `SHA256` fields of
+    /// the index while it is read.
+    pub fn open(&self) -> Result<VerifyingReader<Box<dyn Read + Send>>, RepoError> {
+        let (url, size, sha256) = self.location()?;
+        let options = &self.source.download;
+        let reader: Box<dyn Read + Send> = match &self.source.cache {
+            Some(cache) => Box::new(cache.open(&url, size, sha256, options)?),
+            None => open_url(&url, options)?,
+        };
+        Ok(VerifyingReader::new(reader, &url, size, sha256))
+    }
+
+    /// Download the deb file of this package, or read it from the download
+    /// cache, checking it against the `Size` and `SHA256` fields of the index.
+    /// Failed downloads are retried.
+    pub fn download(&self) -> Result<Vec<u8>, RepoError> {
+        let (url, size, sha256) = self.location()?;
+        match &self.source.cache {
+            Some(cache) => {
+                let file = cache.open(&url, size, sha256, &self.source.download)?;
+                let mut reader = VerifyingReader::new(file, &url, size, sha256);
+                let mut contents = Vec::new();
+                reader
+                    .read_to_end(&mut contents)
+                    .map_err(|error| reader.repo_error(error))?;
+                Ok(contents)
+            }
+            None => download(&url, size, sha256, &self.source.download),
+        }
+    }
+
+    /// Download the deb file of this package into a directory, or find it in
+    /// the download cache, checking it like `download` without keeping it in
+    /// memory. Returns the path of the file.
+    pub fn download_to(&self, dir: &Path) -> Result<PathBuf, RepoError> {
+        let (url, size, sha256) = self.location()?;
+        let options = &self.source.download;
+        match &self.source.cache {
+            Some(cache) => {
+                let path = cache.path(&url, size, sha256, options)?;
+                let file = File::open(&path).map_err(|error| RepoError::Fetch {
+                    url: path.display().to_string(),
+                    error: error.to_string(),
+                })?;
+                VerifyingReader::new(file, &url, size, sha256).finish()?;
+                Ok(path)
+            }
+            None => {
+                let path = dir.join(checked_sha256(&url, sha256)?);
+                write_atomically(&path, |file| {
+                    download_into(&url, size, sha256, options, file)
+                })?;
+                Ok(path)
+            }
+        }
+    }
+}
+
+/// Download the deb files of packages into a directory, at most
+/// `concurrency` at a time, returning their paths. Packages from a source
+/// with a download cache are kept there instead. `progress` is called with
+/// the number of finished downloads after each one. After a failed download
+/// no new ones are started, and the first error is returned.
+pub fn download_packages(
+    packages: &[&Package],
+    dir: &Path,
+    concurrency: usize,
+    progress: &(dyn Fn(&Package, usize, usize) + Sync),
+) -> Result<Vec<PathBuf>, RepoError> {
+    let next = AtomicUsize::new(0);
+    let finished = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new(packages.iter().map(|_| None).collect::<Vec<_>>());
+    thread::scope(|scope| {
+        for _ in 0..concurrency.clamp(1, packages.len().max(1)) {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let package = match packages.get(i) {
+                        Some(package) => package,
+                        None => break,
+                    };
+                    let result = package.download_to(dir);
+                    if result.is_ok() {
+                        let finished = finished.fetch_add(1, Ordering::Relaxed) + 1;
+                        progress(package, finished, packages.len());
+                    } else {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// How to choose between packages of the same name from several sources.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourcePriority {
+    /// Only use the versions from the first source listing the package.
+    FirstSource,
+    /// Use the versions from all sources, so that the highest version wins.
+    /// The first source wins among equal versions.
+    HighestVersion,
+}
+
+/// All versions of the packages in a repository, by package name.
+#[derive(Clone, Default)]
+pub struct PackageIndex {
+    /// The versions of each package, sorted from oldest to newest.
+    packages: HashMap<String, Vec<Package>>,
+}
+
+impl PackageIndex {
+    pub fn new() -> PackageIndex {
+        PackageIndex::default()
+    }
+
+    /// Add a package, keeping the versions of its name sorted. A package
+    /// with the same version as one already added is placed after it.
+    pub fn insert(&mut self, package: Package) {
+        let versions = self
+            .packages
+            .entry(package.fields["Package"].to_string())
+            .or_default();
+        let position = versions.partition_point(|existing| existing.version <= package.version);
+        versions.insert(position, package);
+    }
+
+    /// Add the packages of an index with a lower priority, such as one from
+    /// a later source.
+    pub fn merge(&mut self, other: PackageIndex, priority: SourcePriority) {
+        for (name, other_versions) in other.packages {
+            let versions = self.packages.entry(name).or_default();
+            match priority {
+                SourcePriority::FirstSource if !versions.is_empty() => {}
+                SourcePriority::FirstSource => *versions = other_versions,
+                SourcePriority::HighestVersion => {
+                    // Place them before equal versions, which thereby win.
+                    for package in other_versions {
+                        let position =
+                            versions.partition_point(|existing| existing.version < package.version);
+                        versions.insert(position, package);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.packages.contains_key(name)
+    }
+
+    /// The package names in the index, in no particular order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.packages.keys().map(String::as_str)
+    }
+
+    /// All versions of a package, from oldest to newest.
+    pub fn versions(&self, name: &str) -> &[Package] {
+        self.packages.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// The newest version of a package.
+    pub fn newest(&self, name: &str) -> Option<&Package> {
+        self.versions(name).last()
+    }
+
+    /// The newest version of a package for which `predicate` holds.
+    pub fn newest_matching(
+        &self,
+        name: &str,
+        predicate: impl Fn(&DebVersion) -> bool,
+    ) -> Option<&Package> {
+        self.versions(name)
+            .iter()
+            .rev()
+            .find(|package| predicate(&package.version))
+    }
+
+    /// The newest version of a package which is at least `version`.
+    pub fn newest_at_least(&self, name: &str, version: &DebVersion) -> Option<&Package> {
+        self.newest_matching(name, |candidate| candidate >= version)
+    }
+}
+
+/// Fetch an index file in `dists/<suite>/`, preferring the xz and gzip
+/// compressed variants listed in the Release file over the uncompressed one.
+/// The fetched file is checked against its size and hash in the Release file
+/// before being decompressed.
+fn fetch_index(source: &RepoSource, release: &Release, path: &str) -> Result<Vec<u8>, RepoError> {
+    let mut last_error = None;
+    for &compression in &[Compression::Xz, Compression::Gzip, Compression::None] {
+        let compressed_path = format!("{}{}", path, compression.extension());
+        if compression != Compression::None && !release.files.contains_key(&compressed_path) {
+            continue;
+        }
+        // Fetching by hash gives the file listed in the Release file even if
+        // the repository is updated in the meantime.
+        let url = match release.files.get(&compressed_path) {
+            Some(file) if release.acquire_by_hash() => {
+                source.dist_url(&by_hash_path(&compressed_path, &file.sha256))
+            }
+            _ => source.dist_url(&compressed_path),
+        };
+        let fetched = match (&source.cache, release.files.get(&compressed_path)) {
+            (Some(cache), Some(file)) => {
+                cache.fetch(&url, file.size, &file.sha256, &source.download)
+            }
+            _ => source.fetch_bytes(&url),
+        };
+        let result = fetched.and_then(|contents| {
+            release
+                .verify_file(&compressed_path, &contents)
+                .map_err(|error| RepoError::Verification {
+                    url: url.clone(),
+                    error,
+                })?;
+            let mut decompressed = Vec::new();
+            compression
+                .decompress(&contents[..])
+                .and_then(|mut reader| Ok(reader.read_to_end(&mut decompressed)?))
+                .map_err(|error| RepoError::Fetch {
+                    url: url.clone(),
+                    error: error.to_string(),
+                })?;
+            Ok(decompressed)
+        });
+        match result {
+            Ok(contents) => return Ok(contents),
+            Err(error) => last_error = Some(error),
+        }
+    }
+    Err(last_error.expect("The uncompressed index is always tried"))
+}
+
+/// Fetch the Packages index of a repository, after checking it against the
+/// size and hash listed in the Release file.
+pub fn fetch_repo(source: &RepoSource) -> Result<PackageIndex, RepoError> {
+    let release = fetch_release(source)?;
+    let contents = fetch_index(source, &release, &source.packages_path())?;
+    parse_packages(BufReader::new(&contents[..]), Arc::new(source.clone())).map_err(|error| {
+        RepoError::Parse {
+            url: source.packages_url(),
+            error,
+        }
+    })
+}
+
+/// Fetch the Packages indexes of several sources, such as the components of
+/// a repository, into one index. The sources are given in order of priority,
+/// and each package records the source it came from.
+pub fn fetch_universe(
+    sources: &[RepoSource],
+    priority: SourcePriority,
+) -> Result<PackageIndex, RepoError> {
+    let mut universe = PackageIndex::new();
+    for source in sources {
+        universe.merge(fetch_repo(source)?, priority);
+    }
+    Ok(universe)
+}
+
+fn parse_packages(
+    reader: impl BufRead,
+    source: Arc<RepoSource>,
+) -> Result<PackageIndex, ParseError> {
+    let mut result = PackageIndex::new();
+    let mut paragraphs = Paragraphs::new(reader);
+    while let Some(paragraph) = paragraphs.next() {
+        let paragraph = paragraph?;
+        let line = paragraphs.paragraph_line();
+        let syntax_error = |message: String| ParseError::Syntax { line, message };
+        if !paragraph.contains("Package") {
+            return Err(syntax_error("missing Package field".to_string()));
+        }
+        let version = paragraph
+            .get("Version")
+            .ok_or_else(|| syntax_error("missing Version field".to_string()))?
+            .parse()
+            .map_err(|e: VersionError| syntax_error(e.to_string()))?;
+        result.insert(Package {
+            fields: paragraph,
+            version,
+            source: Arc::clone(&source),
+        });
+    }
+    Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -75,6 +1456,7 @@ Installed-Size: 2772
 Maintainer: Fredrik Fornwall @fornwall
 Version: 7.1.2.33-2
 Description: Android Asset Packaging Tool
+ Tool for compiling and packaging Android application resources.
 Homepage: http://elinux.org/Android_aapt
 Depends: libexpat, libpng, libzopfli
 Filename: dists/stable/main/binary-aarch64/aapt_7.1.2.33-2_aarch64.deb
@@ -93,25 +1475,929 @@ Filename: dists/stable/main/binary-aarch64/abduco_0.6_aarch64.deb
 Size: 8964
 SHA256: 1adc99a0257cec154bdc89a1c6974ecc373b6bd4018e5851d15d28e7cdf57ad3";
         let cursor = Cursor::new(packages_str);
-        let packages = parse_packages(cursor);
+        let packages = parse_packages(cursor, Arc::new(RepoSource::default())).unwrap();
 
-        let aapt_package = &packages["aapt"];
-        assert_eq!("aapt", aapt_package.fields["Package"]);
-        assert_eq!("2772", aapt_package.fields["Installed-Size"]);
+        let aapt_package = packages.newest("aapt").unwrap();
+        assert_eq!("aapt", &aapt_package.fields["Package"]);
+        assert_eq!("2772", &aapt_package.fields["Installed-Size"]);
+        assert_eq!(
+            "Android Asset Packaging Tool\n Tool for compiling and packaging Android application resources.",
+            &aapt_package.fields["Description"]
+        );
+
+        let abduco_package = packages.newest("abduco").unwrap();
+        assert_eq!("abduco", &abduco_package.fields["Package"]);
+        assert_eq!(
+            "libutil, dvtm",
+            abduco_package.relations("Depends").unwrap().to_string()
+        );
+        assert!(abduco_package.relations("Conflicts").unwrap().0.is_empty());
+        assert_eq!("68", &abduco_package.fields["Installed-Size"]);
+        assert_eq!(
+            "https://termux.org/packages/dists/stable/main/binary-aarch64/abduco_0.6_aarch64.deb",
+            abduco_package.package_url()
+        );
+    }
 
-        let abduco_package = &packages["abduco"];
-        assert_eq!("abduco", abduco_package.fields["Package"]);
-        assert_eq!("68", abduco_package.fields["Installed-Size"]);
+    #[test]
+    fn test_parse_packages_errors() {
+        let parse = |text: &str| parse_packages(text.as_bytes(), Arc::new(RepoSource::default()));
+        assert!(matches!(
+            parse("Package: a\nVersion: 1\n\nArchitecture: all\nVersion: 1\n"),
+            Err(ParseError::Syntax { line: 4, .. })
+        ));
+        assert!(matches!(
+            parse("Package: a\nVersion: 1\n\n\nPackage: b\nArchitecture: all\n"),
+            Err(ParseError::Syntax { line: 5, .. })
+        ));
+        assert!(matches!(
+            parse("Package: a\nVersion: 1\n\nPackage: b\nVersion: a:1\n"),
+            Err(ParseError::Syntax { line: 4, .. })
+        ));
+    }
+
+    #[test]
+    fn test_package_index() {
+        let packages_str = "Package: a\nVersion: 1.10\n\nPackage: a\nVersion: 1.9\n\n\
+                            Package: a\nVersion: 1.10~rc1\n\nPackage: b\nVersion: 1:0.1\n";
+        let packages =
+            parse_packages(Cursor::new(packages_str), Arc::new(RepoSource::default())).unwrap();
+        let versions: Vec<_> = packages
+            .versions("a")
+            .iter()
+            .map(|package| package.version.to_string())
+            .collect();
+        assert_eq!(vec!["1.9", "1.10~rc1", "1.10"], versions);
+        assert_eq!("1.10", &packages.newest("a").unwrap().fields["Version"]);
+        assert_eq!(
+            "1.9",
+            &packages
+                .newest_matching("a", |version| version < &"1.10~".parse().unwrap())
+                .unwrap()
+                .fields["Version"]
+        );
+        assert!(packages
+            .newest_at_least("b", &"2.0".parse().unwrap())
+            .is_some());
+        assert!(packages
+            .newest_at_least("a", &"2.0".parse().unwrap())
+            .is_none());
+        assert!(packages.newest("c").is_none());
+        assert!(packages.versions("c").is_empty());
+
+        let invalid = "Package: a\nVersion: a.1\n";
+        assert!(parse_packages(Cursor::new(invalid), Arc::new(RepoSource::default())).is_err());
+    }
+
+    #[test]
+    fn test_repo_source() {
+        let config_dir = tempfile::tempdir().unwrap();
+        let config_path = config_dir.path().join("repo.conf");
+        std::fs::write(
+            &config_path,
+            "URL: https://example.com/x11/\nComponent: x11\n",
+        )
+        .unwrap();
+        let source = RepoSource::read_config(&config_path).unwrap();
+        assert_eq!("stable", source.suite);
+        assert_eq!(
+            "https://example.com/x11/dists/stable/x11/binary-arm/Packages",
+            source.with_arch("arm").packages_url()
+        );
+    }
+
+    #[test]
+    fn test_parse_sources_list() {
+        let sources = parse_sources_list(
+            "# Termux repositories\n\
+             deb https://packages.termux.org/apt/termux-main stable main # Main\n\
+             deb-src https://packages.termux.org/apt/termux-main stable main\n\
+             \n\
+             deb [arch=aarch64,arm signed-by=/etc/apt/x11.gpg trusted=no] https://x11.example x11 main science\n",
+        )
+        .unwrap();
+        assert_eq!(3, sources.len());
+        assert_eq!(
+            "https://packages.termux.org/apt/termux-main",
+            sources[0].url
+        );
+        assert_eq!(
+            ("stable", "main"),
+            (&sources[0].suite[..], &sources[0].component[..])
+        );
+        assert_eq!(None, sources[0].signed_by);
+        assert!(sources[0].supports_arch("i686"));
+
+        assert_eq!(
+            ("x11", "science"),
+            (&sources[2].suite[..], &sources[2].component[..])
+        );
+        assert_eq!(
+            Some(PathBuf::from("/etc/apt/x11.gpg")),
+            sources[2].signed_by
+        );
+        assert_eq!("aarch64", sources[2].arch);
+        assert!(sources[2].supports_arch("arm"));
+        assert!(sources[2].supports_arch("all"));
+        assert!(!sources[2].supports_arch("i686"));
+
+        for (invalid, line) in &[
+            (
+                "deb https://example.com stable main\ndeb https://example.com\n",
+                2,
+            ),
+            ("rpm https://example.com stable main", 1),
+            ("deb [arch=arm https://example.com stable main", 1),
+            ("deb https://example.com ./", 1),
+        ] {
+            assert!(matches!(
+                parse_sources_list(invalid),
+                Err(ParseError::Syntax { line: l, .. }) if l == *line
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_deb822_sources() {
+        let sources = parse_deb822_sources(
+            "Types: deb deb-src\n\
+             URIs: https://a.example https://b.example\n\
+             Suites: stable\n\
+             Components: main\n \
+             x11\n\
+             Architectures: aarch64\n\
+             Signed-By: /etc/apt/keyring.gpg\n\
+             \n\
+             # Disabled\n\
+             Types: deb\n\
+             URIs: https://c.example\n\
+             Suites: stable\n\
+             Components: main\n\
+             Enabled: no\n\
+             \n\
+             Types: deb-src\n\
+             URIs: https://d.example\n\
+             Suites: stable\n\
+             Components: main\n",
+        )
+        .unwrap();
+        let names: Vec<_> = sources
+            .iter()
+            .map(|source| format!("{} {}", source.url, source.component))
+            .collect();
+        assert_eq!(
+            vec![
+                "https://a.example main",
+                "https://a.example x11",
+                "https://b.example main",
+                "https://b.example x11"
+            ],
+            names
+        );
+        assert!(sources
+            .iter()
+            .all(|source| source.signed_by == Some(PathBuf::from("/etc/apt/keyring.gpg"))));
+        assert!(!sources[0].supports_arch("arm"));
+
+        assert!(matches!(
+            parse_deb822_sources(
+                "Types: deb\nURIs: https://a.example\nSuites: stable\nComponents: main\n\n\
+                 # Flat\nTypes: deb\nURIs: https://a.example\nSuites: stable\n"
+            ),
+            Err(ParseError::Syntax { line: 7, .. })
+        ));
+        assert!(parse_deb822_sources(
+            "Types: deb\nURIs: https://a.example\nSuites: stable\nComponents: main\n\
+             Signed-By:\n -----BEGIN PGP PUBLIC KEY BLOCK-----\n"
+        )
+        .is_err());
+    }
+
+    const PACKAGES: &str = "Package: a\nVersion: 1\nFilename: pool/a_1_all.deb\n";
+
+    /// Create a repository with a Packages index for the "all" architecture
+    /// and a Release file listing it.
+    fn create_local_repo(repo_path: &Path) -> RepoSource {
+        let dist_path = repo_path.join("dists/stable");
+        std::fs::create_dir_all(dist_path.join("main/binary-all")).unwrap();
+        std::fs::write(dist_path.join("main/binary-all/Packages"), PACKAGES).unwrap();
+        std::fs::write(
+            dist_path.join("Release"),
+            format!(
+                "Suite: stable\nSHA256:\n {} {} main/binary-all/Packages\n",
+                sha256_hex(PACKAGES.as_bytes()),
+                PACKAGES.len()
+            ),
+        )
+        .unwrap();
+        RepoSource {
+            url: format!("file://{}", repo_path.display()),
+            ..RepoSource::default()
+        }
+        .with_arch("all")
+    }
+
+    #[test]
+    fn test_fetch_local_repo() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let source = create_local_repo(repo_dir.path());
+
+        let packages = fetch_repo(&source).unwrap();
+        assert_eq!(
+            format!("file://{}/pool/a_1_all.deb", repo_dir.path().display()),
+            packages.newest("a").unwrap().package_url()
+        );
+        assert!(fetch_repo(&source.with_arch("arm")).is_err());
+
+        std::fs::write(
+            repo_dir
+                .path()
+                .join("dists/stable/main/binary-all/Packages"),
+            PACKAGES.replace("Version: 1", "Version: 2"),
+        )
+        .unwrap();
+        assert!(matches!(
+            fetch_repo(&source),
+            Err(RepoError::Verification { .. })
+        ));
+    }
+
+    #[test]
+    fn test_fetch_universe() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let dist_path = repo_dir.path().join("dists/stable");
+        for (component, packages) in &[
+            ("main", "Package: a\nVersion: 1.0\n\nPackage: b\nVersion: 2.0\n"),
+            (
+                "x11",
+                "Package: a\nVersion: 2.0\n\nPackage: b\nVersion: 2.0\n\nPackage: c\nVersion: 1.0\n",
+            ),
+        ] {
+            let binary_path = dist_path.join(component).join("binary-all");
+            std::fs::create_dir_all(&binary_path).unwrap();
+            std::fs::write(binary_path.join("Packages"), packages).unwrap();
+        }
+        let options = ReleaseOptions {
+            origin: None,
+            suite: "stable".to_string(),
+            codename: None,
+            valid_for: None,
+            by_hash: false,
+        };
+        write_release(repo_dir.path(), &options, None).unwrap();
+        let sources: Vec<_> = ["main", "x11"]
+            .iter()
+            .map(|component| RepoSource {
+                url: format!("file://{}", repo_dir.path().display()),
+                component: component.to_string(),
+                ..RepoSource::default().with_arch("all")
+            })
+            .collect();
+        let origin = |package: Option<&Package>| {
+            let package = package.unwrap();
+            format!("{} {}", package.version, package.source.component)
+        };
+
+        let universe = fetch_universe(&sources, SourcePriority::FirstSource).unwrap();
+        assert_eq!("1.0 main", origin(universe.newest("a")));
+        assert_eq!("2.0 main", origin(universe.newest("b")));
+        assert_eq!("1.0 x11", origin(universe.newest("c")));
+        assert_eq!(1, universe.versions("a").len());
+
+        let universe = fetch_universe(&sources, SourcePriority::HighestVersion).unwrap();
+        assert_eq!("2.0 x11", origin(universe.newest("a")));
+        assert_eq!("2.0 main", origin(universe.newest("b")));
+        assert_eq!("1.0 x11", origin(universe.newest("c")));
+        assert_eq!(2, universe.versions("a").len());
+    }
+
+    #[test]
+    fn test_verifying_reader() {
+        let data = b"hello";
+        let sha256 = sha256_hex(data);
+        let read_all = |size: u64, sha256: &str| {
+            let mut reader = VerifyingReader::new(&data[..], "url", size, sha256);
+            let mut contents = Vec::new();
+            reader.read_to_end(&mut contents).map(|_| contents)
+        };
+        assert_eq!(data.to_vec(), read_all(5, &sha256).unwrap());
+        assert_eq!(data.to_vec(), read_all(5, &sha256.to_uppercase()).unwrap());
+        assert!(read_all(4, &sha256).is_err());
+        assert!(read_all(6, &sha256).is_err());
+        assert!(read_all(5, &sha256_hex(b"world")).is_err());
+
+        // A partially read stream is checked by finish().
+        let mut reader = VerifyingReader::new(&data[..], "url", 5, &sha256);
+        reader.read_exact(&mut [0; 2]).unwrap();
+        assert!(reader.finish().is_ok());
+        let mut reader = VerifyingReader::new(&data[..], "url", 5, &sha256_hex(b"world"));
+        reader.read_exact(&mut [0; 2]).unwrap();
+        assert!(matches!(
+            reader.finish(),
+            Err(RepoError::Verification { .. })
+        ));
+    }
+
+    #[test]
+    fn test_open_package() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(repo_dir.path().join("pool")).unwrap();
+        std::fs::write(repo_dir.path().join("pool/a_1_all.deb"), "deb").unwrap();
+        let source = Arc::new(RepoSource {
+            url: format!("file://{}", repo_dir.path().display()),
+            ..RepoSource::default()
+        });
+        let package = |sha256: &str| Package {
+            fields: format!(
+                "Package: a\nFilename: pool/a_1_all.deb\nSize: 3\nSHA256: {}\n",
+                sha256
+            )
+            .parse()
+            .unwrap(),
+            version: "1".parse().unwrap(),
+            source: Arc::clone(&source),
+        };
+
+        let mut contents = String::new();
+        let mut reader = package(&sha256_hex(b"deb")).open().unwrap();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!("deb", contents);
+        assert!(package(&sha256_hex(b"bed"))
+            .open()
+            .unwrap()
+            .read_to_string(&mut contents)
+            .is_err());
+    }
+
+    #[test]
+    fn test_download_cache() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let cache_dir = tempfile::tempdir().unwrap();
+        let source = RepoSource {
+            cache: Some(DownloadCache {
+                dir: cache_dir.path().to_path_buf(),
+                offline: false,
+            }),
+            ..create_local_repo(repo_dir.path())
+        };
+        std::fs::create_dir(repo_dir.path().join("pool")).unwrap();
+        std::fs::write(repo_dir.path().join("pool/a_1_all.deb"), "deb").unwrap();
+        let package = |source: &RepoSource, sha256: &str| Package {
+            fields: format!(
+                "Package: a\nFilename: pool/a_1_all.deb\nSize: 3\nSHA256: {}\n",
+                sha256
+            )
+            .parse()
+            .unwrap(),
+            version: "1".parse().unwrap(),
+            source: Arc::new(source.clone()),
+        };
+        let read_package = |source: &RepoSource, sha256: &str| {
+            let mut contents = String::new();
+            package(source, sha256)
+                .open()
+                .map_err(|e| e.to_string())?
+                .read_to_string(&mut contents)
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(contents)
+        };
+
+        assert!(fetch_repo(&source).unwrap().contains("a"));
+        assert_eq!(
+            Ok("deb".to_string()),
+            read_package(&source, &sha256_hex(b"deb"))
+        );
+        // A download with the wrong hash is not added to the cache.
+        assert!(read_package(&source, &sha256_hex(b"bed")).is_err());
+        assert_eq!(
+            2,
+            std::fs::read_dir(cache_dir.path().join("sha256"))
+                .unwrap()
+                .count()
+        );
+
+        std::fs::remove_dir_all(repo_dir.path()).unwrap();
+        let offline_source = RepoSource {
+            cache: Some(DownloadCache {
+                dir: cache_dir.path().to_path_buf(),
+                offline: true,
+            }),
+            ..source
+        };
+        assert!(fetch_repo(&offline_source).unwrap().contains("a"));
+        assert_eq!(
+            Ok("deb".to_string()),
+            read_package(&offline_source, &sha256_hex(b"deb"))
+        );
+        assert!(read_package(&offline_source, &sha256_hex(b"bed"))
+            .unwrap_err()
+            .contains("(offline)"));
+        assert!(fetch_repo(&offline_source.with_arch("arm"))
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("(offline)"));
+
+        // Downloads to a directory use the file in the cache.
+        let download_dir = tempfile::tempdir().unwrap();
+        let path = package(&offline_source, &sha256_hex(b"deb"))
+            .download_to(download_dir.path())
+            .unwrap();
+        assert!(path.starts_with(cache_dir.path()));
+        assert_eq!(0, std::fs::read_dir(download_dir.path()).unwrap().count());
+    }
+
+    /// A stand-in HTTP server, answering requests with the status and body
+    /// returned by `respond` for the request target and the number of the
+    /// request. A status of 0 closes the connection without an answer.
+    struct TestServer {
+        url: String,
+        requests: Arc<AtomicUsize>,
+        /// The largest number of requests handled at the same time.
+        max_active: Arc<AtomicUsize>,
+    }
+
+    fn serve(
+        respond: impl Fn(&str, usize) -> (u16, Vec<u8>) + Send + Sync + 'static,
+    ) -> TestServer {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = TestServer {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            requests: Arc::new(AtomicUsize::new(0)),
+            max_active: Arc::new(AtomicUsize::new(0)),
+        };
+        let requests = Arc::clone(&server.requests);
+        let max_active = Arc::clone(&server.max_active);
+        let active = Arc::new(AtomicUsize::new(0));
+        let respond = Arc::new(respond);
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let (requests, max_active, active, respond) = (
+                    Arc::clone(&requests),
+                    Arc::clone(&max_active),
+                    Arc::clone(&active),
+                    Arc::clone(&respond),
+                );
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    loop {
+                        let mut request_line = String::new();
+                        if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut header = String::new();
+                        while reader.read_line(&mut header).unwrap_or(0) > 2 {
+                            header.clear();
+                        }
+                        let target = request_line.split_whitespace().nth(1).unwrap_or("");
+                        let number = requests.fetch_add(1, Ordering::SeqCst);
+                        let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_active.fetch_max(now_active, Ordering::SeqCst);
+                        let (status, body) = respond(target, number);
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        if status == 0 {
+                            return;
+                        }
+                        let header = format!(
+                            "HTTP/1.1 {} Test\r\nContent-Length: {}\r\n\r\n",
+                            status,
+                            body.len()
+                        );
+                        if stream.write_all(header.as_bytes()).is_err()
+                            || stream.write_all(&body).is_err()
+                        {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        server
+    }
+
+    fn http_package(
+        url: &str,
+        filename: &str,
+        contents: &[u8],
+        download: DownloadOptions,
+    ) -> Package {
+        Package {
+            fields: format!(
+                "Package: a\nFilename: {}\nSize: {}\nSHA256: {}\n",
+                filename,
+                contents.len(),
+                sha256_hex(contents)
+            )
+            .parse()
+            .unwrap(),
+            version: "1".parse().unwrap(),
+            source: Arc::new(RepoSource {
+                url: url.to_string(),
+                download,
+                ..RepoSource::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn test_download_retries() {
+        let options = DownloadOptions {
+            retry_delay: Duration::from_millis(1),
+            ..DownloadOptions::default()
+        };
+        let download = |server: &TestServer, options: &DownloadOptions| {
+            http_package(&server.url, "pool/a.deb", b"deb", options.clone()).download()
+        };
+
+        // A dropped connection and a server error are retried.
+        let server = serve(|_, number| match number {
+            0 => (0, Vec::new()),
+            1 => (503, Vec::new()),
+            _ => (200, b"deb".to_vec()),
+        });
+        assert_eq!(b"deb".to_vec(), download(&server, &options).unwrap());
+        assert_eq!(3, server.requests.load(Ordering::SeqCst));
+
+        let server = serve(|_, _| (503, Vec::new()));
+        let few_retries = DownloadOptions {
+            retries: 1,
+            ..options.clone()
+        };
+        assert!(download(&server, &few_retries).is_err());
+        assert_eq!(2, server.requests.load(Ordering::SeqCst));
+
+        // Missing files and wrong contents are not.
+        let server = serve(|_, _| (404, Vec::new()));
+        assert!(matches!(
+            download(&server, &options),
+            Err(RepoError::Fetch { .. })
+        ));
+        assert_eq!(1, server.requests.load(Ordering::SeqCst));
+        let server = serve(|_, _| (200, b"bed".to_vec()));
+        assert!(matches!(
+            download(&server, &options),
+            Err(RepoError::Verification { .. })
+        ));
+        assert_eq!(1, server.requests.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_download_timeout() {
+        let server = serve(|_, _| {
+            thread::sleep(Duration::from_secs(2));
+            (200, b"deb".to_vec())
+        });
+        let options = DownloadOptions {
+            retries: 0,
+            timeout: Duration::from_millis(200),
+            ..DownloadOptions::default()
+        };
+        let start = std::time::Instant::now();
+        let package = http_package(&server.url, "pool/a.deb", b"deb", options);
+        assert!(matches!(package.download(), Err(RepoError::Fetch { .. })));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_download_packages() {
+        let server = serve(|target, _| {
+            thread::sleep(Duration::from_millis(50));
+            (200, target.as_bytes().to_vec())
+        });
+        let packages: Vec<_> = (0..6)
+            .map(|i| {
+                let filename = format!("pool/{}.deb", i);
+                let contents = format!("/{}", filename);
+                http_package(
+                    &server.url,
+                    &filename,
+                    contents.as_bytes(),
+                    DownloadOptions::default(),
+                )
+            })
+            .collect();
+        let progress = Mutex::new(Vec::new());
+        let download_dir = tempfile::tempdir().unwrap();
+        let paths = download_packages(
+            &packages.iter().collect::<Vec<_>>(),
+            download_dir.path(),
+            2,
+            &|_, finished, total| progress.lock().unwrap().push((finished, total)),
+        )
+        .unwrap();
+        for (i, path) in paths.iter().enumerate() {
+            assert!(path.starts_with(download_dir.path()));
+            assert_eq!(
+                format!("/pool/{}.deb", i).as_bytes(),
+                &std::fs::read(path).unwrap()[..]
+            );
+        }
+        assert_eq!(2, server.max_active.load(Ordering::SeqCst));
+        let mut progress = progress.into_inner().unwrap();
+        progress.sort_unstable();
+        assert_eq!((1..=6).map(|i| (i, 6)).collect::<Vec<_>>(), progress);
+
+        let corrupt = http_package(&server.url, "pool/6.deb", b"", DownloadOptions::default());
+        let mut with_corrupt: Vec<_> = packages.iter().collect();
+        with_corrupt.insert(3, &corrupt);
+        assert!(download_packages(&with_corrupt, download_dir.path(), 2, &|_, _, _| {}).is_err());
+    }
+
+    #[test]
+    fn test_download_through_proxy() {
+        let proxy = serve(|target, _| match target {
+            "http://packages.invalid/pool/a.deb" => (200, b"deb".to_vec()),
+            _ => (404, Vec::new()),
+        });
+        let options = DownloadOptions {
+            proxy: Some(proxy.url.clone()),
+            ..DownloadOptions::default()
+        };
+        let package = http_package("http://packages.invalid", "pool/a.deb", b"deb", options);
+        assert_eq!(b"deb".to_vec(), package.download().unwrap());
+    }
+
+    #[test]
+    fn test_fetch_compressed_index() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let source = create_local_repo(repo_dir.path());
+        let binary_path = repo_dir.path().join("dists/stable/main/binary-all");
+        std::fs::remove_file(binary_path.join("Packages")).unwrap();
+
+        let xz_contents = Compression::Xz.compress(PACKAGES.as_bytes()).unwrap();
+        let gz_contents = Compression::Gzip.compress(PACKAGES.as_bytes()).unwrap();
+        std::fs::write(binary_path.join("Packages.xz"), &xz_contents).unwrap();
+        std::fs::write(binary_path.join("Packages.gz"), &gz_contents).unwrap();
+        let mut release = String::from("Suite: stable\nSHA256:\n");
+        for (contents, path) in &[
+            (&xz_contents, "main/binary-all/Packages.xz"),
+            (&gz_contents, "main/binary-all/Packages.gz"),
+        ] {
+            release += &format!(" {} {} {}\n", sha256_hex(contents), contents.len(), path);
+        }
+        std::fs::write(repo_dir.path().join("dists/stable/Release"), release).unwrap();
+
+        assert!(fetch_repo(&source).unwrap().contains("a"));
+        // A corrupt Packages.xz makes it fall back to Packages.gz.
+        std::fs::write(binary_path.join("Packages.xz"), &gz_contents).unwrap();
+        assert!(fetch_repo(&source).unwrap().contains("a"));
+        std::fs::remove_file(binary_path.join("Packages.gz")).unwrap();
+        assert!(matches!(fetch_repo(&source), Err(RepoError::Fetch { .. })));
+    }
+
+    #[test]
+    fn test_fetch_signed_repo() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let dist_path = repo_dir.path().join("dists/stable");
+        std::fs::create_dir_all(dist_path.join("main/binary-all")).unwrap();
+        std::fs::write(dist_path.join("main/binary-all/Packages"), "").unwrap();
+        std::fs::write(dist_path.join("Release"), include_str!("testdata/Release")).unwrap();
+        let keyring_path = repo_dir.path().join("keyring.asc");
+        std::fs::write(&keyring_path, include_str!("testdata/keyring.asc")).unwrap();
+        let source = RepoSource {
+            url: format!("file://{}", repo_dir.path().display()),
+            signed_by: Some(keyring_path),
+            ..RepoSource::default()
+        }
+        .with_arch("all");
+
+        // Release without Release.gpg.
+        assert!(fetch_repo(&source).is_err());
+        assert!(fetch_repo(&RepoSource {
+            signed_by: None,
+            ..source.clone()
+        })
+        .is_ok());
+
+        std::fs::write(
+            dist_path.join("Release.gpg"),
+            &include_bytes!("testdata/Release.gpg")[..],
+        )
+        .unwrap();
+        assert!(fetch_repo(&source).is_ok());
+
+        std::fs::write(
+            dist_path.join("InRelease"),
+            include_str!("testdata/InRelease").replace("Origin: test", "Origin: evil"),
+        )
+        .unwrap();
+        assert!(matches!(
+            fetch_release(&source),
+            Err(RepoError::Verification { .. })
+        ));
+        std::fs::write(
+            dist_path.join("InRelease"),
+            include_str!("testdata/InRelease"),
+        )
+        .unwrap();
+        assert_eq!(
+            Some("test"),
+            fetch_release(&source).unwrap().fields.get("Origin")
+        );
+    }
+
+    #[test]
+    fn test_write_release() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo_path = repo_dir.path();
+        let source = create_local_repo(repo_path);
+        std::fs::create_dir_all(repo_path.join("dists/stable/x11/binary-arm")).unwrap();
+        std::fs::write(
+            repo_path.join("dists/stable/x11/binary-arm/Packages.gz"),
+            Compression::Gzip.compress(b"").unwrap(),
+        )
+        .unwrap();
+        let options = ReleaseOptions {
+            origin: Some("Termux".to_string()),
+            suite: "stable".to_string(),
+            codename: None,
+            valid_for: Some(chrono::Duration::days(7)),
+            by_hash: false,
+        };
+        let signing_key = SigningKey::from_bytes(include_bytes!("testdata/secret.asc")).unwrap();
+        write_release(repo_path, &options, Some(&signing_key)).unwrap();
+
+        let keyring_path = repo_path.join("keyring.asc");
+        std::fs::write(&keyring_path, include_str!("testdata/keyring.asc")).unwrap();
+        let source = RepoSource {
+            signed_by: Some(keyring_path),
+            ..source
+        };
+        let release = fetch_release(&source).unwrap();
+        assert_eq!(Some("Termux"), release.fields.get("Origin"));
+        assert_eq!(Some("stable"), release.fields.get("Codename"));
+        assert_eq!(Some("all arm"), release.fields.get("Architectures"));
+        assert_eq!(Some("main x11"), release.fields.get("Components"));
+        assert!(release.fields.get("Valid-Until").is_some());
+        assert_eq!(
+            vec!["main/binary-all/Packages", "x11/binary-arm/Packages.gz"],
+            {
+                let mut paths: Vec<_> = release.files.keys().collect();
+                paths.sort();
+                paths
+            }
+        );
+        assert_eq!(2, release.fields.get_lines("MD5Sum").count());
+        assert!(fetch_repo(&source).unwrap().contains("a"));
+
+        std::fs::remove_file(repo_path.join("dists/stable/InRelease")).unwrap();
+        assert!(fetch_release(&source).is_ok());
+    }
+
+    #[test]
+    fn test_write_unsigned_release_after_signed() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo_path = repo_dir.path();
+        let source = create_local_repo(repo_path);
+        let mut options = ReleaseOptions {
+            origin: Some("Termux".to_string()),
+            suite: "stable".to_string(),
+            codename: None,
+            valid_for: None,
+            by_hash: false,
+        };
+        let signing_key = SigningKey::from_bytes(include_bytes!("testdata/secret.asc")).unwrap();
+        write_release(repo_path, &options, Some(&signing_key)).unwrap();
+        let suite_dir = repo_path.join("dists/stable");
+        assert!(suite_dir.join("InRelease").exists());
+        assert!(suite_dir.join("Release.gpg").exists());
+
+        options.origin = Some("Unsigned".to_string());
+        write_release(repo_path, &options, None).unwrap();
+        let mut names: Vec<_> = std::fs::read_dir(&suite_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["Release", "main"], names);
+        let release = fetch_release(&source).unwrap();
+        assert_eq!(Some("Unsigned"), release.fields.get("Origin"));
+
+        let keyring_path = repo_path.join("keyring.asc");
+        std::fs::write(&keyring_path, include_str!("testdata/keyring.asc")).unwrap();
+        let source = RepoSource {
+            signed_by: Some(keyring_path),
+            ..source
+        };
+        assert!(fetch_release(&source).is_err());
+    }
+
+    #[test]
+    fn test_by_hash() {
+        assert_eq!(
+            "main/binary-arm/by-hash/SHA256/abc",
+            by_hash_path("main/binary-arm/Packages.xz", "abc")
+        );
+
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo_path = repo_dir.path();
+        let source = create_local_repo(repo_path);
+        let binary_path = repo_path.join("dists/stable/main/binary-all");
+        let options = ReleaseOptions {
+            origin: None,
+            suite: "stable".to_string(),
+            codename: None,
+            valid_for: None,
+            by_hash: true,
+        };
+        let by_hash_file = |contents: &str| {
+            binary_path
+                .join("by-hash/SHA256")
+                .join(sha256_hex(contents.as_bytes()))
+        };
+
+        write_release(repo_path, &options, None).unwrap();
+        let release = fetch_release(&source).unwrap();
+        assert!(release.acquire_by_hash());
+        assert_eq!(
+            vec!["main/binary-all/Packages"],
+            release.files.keys().collect::<Vec<_>>()
+        );
+        assert!(by_hash_file(PACKAGES).exists());
+
+        // An update of the index while a client has the old Release file.
+        let updated = PACKAGES.replace("Version: 1", "Version: 2");
+        std::fs::write(binary_path.join("Packages"), &updated).unwrap();
+        let fetch_with_old_release = || {
+            let contents = fetch_index(&source, &release, &source.packages_path()).unwrap();
+            String::from_utf8(contents).unwrap()
+        };
+        write_release(repo_path, &options, None).unwrap();
+        assert_eq!(PACKAGES, fetch_with_old_release());
+        assert_eq!(
+            "2",
+            fetch_repo(&source)
+                .unwrap()
+                .newest("a")
+                .unwrap()
+                .version
+                .to_string()
+        );
+
+        // Copies not listed in the current or previous Release are removed.
+        std::fs::write(binary_path.join("Packages"), "").unwrap();
+        write_release(repo_path, &options, None).unwrap();
+        assert!(!by_hash_file(PACKAGES).exists());
+        assert!(by_hash_file(&updated).exists());
+        assert!(by_hash_file("").exists());
+    }
+
+    #[test]
+    fn test_parse_inrelease() {
+        let inrelease = "-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA512
+
+Origin: termux
+Suite: stable
+SHA256:
+ E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855 0 main/binary-arm/Packages
+- -Dash-Escaped: yes
+-----BEGIN PGP SIGNATURE-----
+
+iQEzBAEBCgAdFiEE
+-----END PGP SIGNATURE-----
+";
+        let release = Release::from_clearsigned(inrelease).unwrap();
+        assert_eq!(Some("termux"), release.fields.get("Origin"));
+        assert_eq!(Some("yes"), release.fields.get("-Dash-Escaped"));
+        assert!(release.verify_file("main/binary-arm/Packages", b"").is_ok());
+        assert!(release
+            .verify_file("main/binary-arm/Packages", b"a")
+            .is_err());
+        assert!(release
+            .verify_file("main/binary-all/Packages", b"")
+            .is_err());
+        assert!(Release::from_clearsigned("Origin: termux\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_release_errors() {
+        let release = "Origin: termux
+MD5Sum:
+ d41d8cd98f00b204e9800998ecf8427e 0 main/binary-arm/Packages
+ d41d8cd98f00b204e9800998ecf8427e 0 main/binary-all/Packages
+SHA256:
+ e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 0 main/binary-arm/Packages
+ e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855 main/binary-all/Packages
+";
+        assert!(matches!(
+            release.parse::<Release>(),
+            Err(ParseError::Syntax { line: 7, .. })
+        ));
+        assert!(matches!(
+            format!("\n{}", release).parse::<Release>(),
+            Err(ParseError::Syntax { line: 8, .. })
+        ));
     }
 
     #[test]
     fn test_fetch_repo() {
-        let packages = fetch_repo("aarch64");
+        let packages = fetch_repo(&RepoSource::default()).unwrap();
 
-        let abduco_package = &packages["abduco"];
+        let abduco_package = packages.newest("abduco").unwrap();
         assert_eq!(
             "Clean and simple terminal session manager",
-            abduco_package.fields["Description"]
+            &abduco_package.fields["Description"]
         );
     }
 }
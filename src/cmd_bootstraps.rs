@@ -1,31 +1,53 @@
-use crate::apt_repo::fetch_repo;
-use crate::deb_file::{visit_files, DebVisitor};
-use std::collections::HashMap;
+use crate::apt_repo::{
+    download_packages, fetch_repo, Package, PackageIndex, RepoSource, SourcePriority,
+};
+use crate::deb822::Paragraph;
+use crate::deb_file::{visit_files, DebError, DebVisitor, MaintainerScript};
+use crate::deb_resolver::resolve;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
-use std::io::{copy, Read, Result, Write};
+use std::io::{copy, BufReader, Read, Write};
 use std::path::PathBuf;
-use std::sync::Arc;
 use std::thread;
 use std::vec::Vec;
 use zip::write::{FileOptions, ZipWriter};
 
-struct TeeReader<'a, R: 'a + Read, W: 'a + Write> {
-    reader: &'a mut R,
-    writer: &'a mut W,
+pub struct CreateBootstrapVisitor {
+    zip_writer: ZipWriter<File>,
+    /// Control files of the current package to store in var/lib/dpkg/info/,
+    /// as file name suffix and contents.
+    info_files: Vec<(&'static str, Vec<u8>)>,
+    symlinks_txt: Vec<u8>,
 }
 
-impl<'a, R: Read, W: Write> Read for TeeReader<'a, R, W> {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let n = self.reader.read(buf)?;
-        self.writer.write_all(&buf[..n])?;
-        Ok(n)
+impl CreateBootstrapVisitor {
+    fn add_info_file(
+        &mut self,
+        suffix: &'static str,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        let mut contents = Vec::new();
+        copy(file, &mut contents)?;
+        self.info_files.push((suffix, contents));
+        Ok(())
     }
-}
 
-pub struct CreateBootstrapVisitor {
-    zip_writer: ZipWriter<File>,
-    conffiles: Vec<u8>,
-    symlinks_txt: Vec<u8>,
+    fn write_info_files(&mut self, package_name: &str) {
+        for (suffix, contents) in std::mem::take(&mut self.info_files) {
+            let is_script = MaintainerScript::ALL
+                .iter()
+                .any(|script| script.name() == suffix);
+            let options =
+                FileOptions::default().unix_permissions(if is_script { 0o755 } else { 0o644 });
+            let info_path = format!("var/lib/dpkg/info/{}.{}", package_name, suffix);
+            self.zip_writer
+                .start_file(info_path.as_str(), options)
+                .unwrap_or_else(|err| panic!("Error starting {} zip entry: {}", info_path, err));
+            self.zip_writer
+                .write_all(&contents)
+                .unwrap_or_else(|err| panic!("Error writing {} zip entry: {}", info_path, err));
+        }
+    }
 }
 
 fn write_zip_file(
@@ -41,22 +63,44 @@ fn write_zip_file(
 }
 
 impl DebVisitor for CreateBootstrapVisitor {
-    fn visit_control(&mut self, _fields: HashMap<String, String>) {}
+    fn visit_control(&mut self, _control: Paragraph) -> Result<(), DebError> {
+        Ok(())
+    }
+
+    fn visit_conffiles(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.add_info_file("conffiles", file)
+    }
+
+    fn visit_maintainer_script(
+        &mut self,
+        script: MaintainerScript,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        self.add_info_file(script.name(), file)
+    }
+
+    fn visit_md5sums(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.add_info_file("md5sums", file)
+    }
+
+    fn visit_triggers(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.add_info_file("triggers", file)
+    }
 
-    fn visit_conffiles(&mut self, file: &mut tar::Entry<impl Read>) {
-        copy(file, &mut self.conffiles).expect("Error copying conffiles");
+    fn visit_shlibs(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.add_info_file("shlibs", file)
     }
 
-    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) {
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
         {
             let header = file.header();
             let is_symlink = header.entry_type() == tar::EntryType::Symlink;
             let is_regular = header.entry_type() == tar::EntryType::Regular;
             if !(is_regular || is_symlink) {
-                return;
+                return Ok(());
             }
 
-            let pp = file.path().unwrap();
+            let pp = file.path()?;
             let file_path = pp.to_str().unwrap();
             let relative_path = &file_path[33..];
 
@@ -71,89 +115,153 @@ impl DebVisitor for CreateBootstrapVisitor {
                         .as_bytes(),
                     )
                     .expect("Error appending to SYMLINKS.txt");
-                return;
+                return Ok(());
             }
 
             self.zip_writer
                 .start_file(relative_path, FileOptions::default())
-                .expect("Error writing to zip");
+                .map_err(std::io::Error::from)?;
         }
 
-        let mut md5_context = md5::Context::new();
-        {
-            let mut tee = TeeReader {
-                reader: file,
-                writer: &mut md5_context,
-            };
-            copy(&mut tee, &mut self.zip_writer).expect("Error copying from tar to zip");
-        }
+        copy(file, &mut self.zip_writer)?;
+        Ok(())
     }
 }
 
-pub fn create(output: &str, version: u16) {
+/// The packages to include in bootstraps when no others are given, in
+/// addition to the Essential packages. Their dependencies are added.
+pub const DEFAULT_ROOTS: &[&str] = &[
+    "bash",
+    "busybox",
+    "ca-certificates",
+    "coreutils",
+    "curl",
+    "dash",
+    "grep",
+    "less",
+    "proot",
+    "sed",
+    "termux-am",
+    "termux-exec",
+    "termux-tools",
+];
+
+/// Report a finished download from `download_packages`.
+pub fn print_progress(package: &Package, finished: usize, total: usize) {
+    eprintln!(
+        "[{}/{}] Downloaded {} {} ({})",
+        finished,
+        total,
+        &package.fields["Package"],
+        package.version,
+        package.fields.get("Architecture").unwrap_or("unknown")
+    );
+}
+
+pub fn create(
+    output: &str,
+    version: u16,
+    sources: &[RepoSource],
+    priority: SourcePriority,
+    roots: &[String],
+    concurrency: usize,
+) {
     let path = PathBuf::from(output);
 
-    let bootstrap_packages = Arc::new(vec![
-        "bash",
-        "busybox",
-        "ca-certificates",
-        "coreutils",
-        "curl",
-        "dash",
-        "grep",
-        "less",
-        "libandroid-support",
-        "libbz2",
-        "libcurl",
-        "libgmp",
-        "libiconv",
-        "liblzma",
-        "libnghttp2",
-        "libtalloc",
-        "ncurses",
-        "openssl",
-        "proot",
-        "readline",
-        "sed",
-        "termux-am",
-        "termux-exec",
-        "termux-tools",
-        "zlib",
-    ]);
-
-    let arch_all_packages = Arc::new(fetch_repo("all"));
+    let arch_all_indexes: Vec<PackageIndex> = sources
+        .iter()
+        .map(|source| fetch_repo(&source.with_arch("all")))
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| panic!("{}", e));
+
+    // Resolve the packages of all architectures before creating any zip, so
+    // that an unsatisfiable dependency does not leave partial zips behind.
+    let mut packages_by_arch = Vec::new();
+    for arch in &["arm", "aarch64", "i686", "x86_64"] {
+        let mut packages = PackageIndex::new();
+        for (source, arch_all_index) in sources.iter().zip(&arch_all_indexes) {
+            if source.supports_arch(arch) {
+                let index = fetch_repo(&source.with_arch(arch)).unwrap_or_else(|e| panic!("{}", e));
+                packages.merge(index, priority);
+            }
+            packages.merge(arch_all_index.clone(), priority);
+        }
+        let resolved = resolve(&packages, roots, arch)
+            .unwrap_or_else(|e| panic!("Cannot resolve {} bootstrap packages: {}", arch, e));
+        println!("Bootstrap packages for {}:", arch);
+        for resolved_package in &resolved {
+            let package = resolved_package.package;
+            match &resolved_package.required_by {
+                Some(required_by) => println!(
+                    "  {} {} (required by {})",
+                    &package.fields["Package"], package.version, required_by
+                ),
+                None => println!("  {} {}", &package.fields["Package"], package.version),
+            }
+        }
+        let resolved: Vec<Package> = resolved
+            .into_iter()
+            .map(|resolved_package| resolved_package.package.clone())
+            .collect();
+        packages_by_arch.push((arch, resolved));
+    }
+
+    // Download each package once, also when used for several architectures.
+    let mut seen_urls = HashSet::new();
+    let unique_packages: Vec<&Package> = packages_by_arch
+        .iter()
+        .flat_map(|(_, packages)| packages)
+        .filter(|package| seen_urls.insert(package.package_url()))
+        .collect();
+    // The debs are read from disk while writing the zips, so that they are
+    // never all in memory at once.
+    let download_dir = tempfile::tempdir().expect("Cannot create download directory");
+    let deb_paths = download_packages(
+        &unique_packages,
+        download_dir.path(),
+        concurrency,
+        &print_progress,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+    let deb_path_by_url: HashMap<String, PathBuf> = unique_packages
+        .iter()
+        .map(|package| package.package_url())
+        .zip(deb_paths)
+        .collect();
 
     let mut join_handles = Vec::new();
 
-    for arch in &["arm", "aarch64", "i686", "x86_64"] {
+    for (arch, packages) in packages_by_arch {
+        let bootstrap_packages: Vec<_> = packages
+            .iter()
+            .map(|package| {
+                let package_url = package.package_url();
+                let deb_path = deb_path_by_url[&package_url].clone();
+                (package.fields["Package"].to_string(), package_url, deb_path)
+            })
+            .collect();
         let my_path = path.clone();
-        let my_arch_all_packages = Arc::clone(&arch_all_packages);
-        let my_bootstrap_packages = Arc::clone(&bootstrap_packages);
         join_handles.push(thread::spawn(move || {
-            let http_client = reqwest::blocking::Client::new();
-
             let output_zip_path =
                 my_path.join(format!("android10-v{}-bootstrap-{}.zip", version, arch));
             let output_zip_file = OpenOptions::new()
                 .write(true)
                 .create(true)
                 .truncate(true)
-                .open(output_zip_path)
+                .open(&output_zip_path)
                 .expect("Cannot open zip for writing");
 
             let mut visitor = CreateBootstrapVisitor {
                 zip_writer: ZipWriter::new(output_zip_file),
-                conffiles: Vec::new(),
+                info_files: Vec::new(),
                 symlinks_txt: Vec::new(),
             };
 
             // The app needs directories to appear before files.
-            for dir in vec!["tmp/"].iter() {
-                visitor
-                    .zip_writer
-                    .add_directory(*dir, FileOptions::default())
-                    .expect("Error creating dir");
-            }
+            visitor
+                .zip_writer
+                .add_directory("tmp/", FileOptions::default())
+                .expect("Error creating dir");
 
             // This needs to be an empty file, else removing a package fails:
             visitor
@@ -161,34 +269,16 @@ pub fn create(output: &str, version: u16) {
                 .start_file("var/lib/dpkg/available", FileOptions::default())
                 .expect("Unable to create var/lib/dpkg/available");
 
-            let packages = fetch_repo(arch);
-            for bootstrap_package_name in my_bootstrap_packages.iter() {
-                let bootstrap_package = packages
-                    .get(*bootstrap_package_name)
-                    .or_else(|| my_arch_all_packages.get(*bootstrap_package_name))
-                    .unwrap_or_else(|| panic!("Cannot find package '{}'", bootstrap_package_name));
-
-                let package_url = bootstrap_package.package_url();
-
-                let mut response = http_client
-                    .get(&package_url)
-                    .send()
-                    .unwrap_or_else(|_| panic!("Failed fetching {}", package_url));
-
-                visitor.conffiles.clear();
-                visit_files(&mut response, &mut visitor);
-
-                {
-                    if !visitor.conffiles.is_empty() {
-                        let conffiles_path =
-                            format!("var/lib/dpkg/info/{}.conffiles", bootstrap_package_name);
-                        write_zip_file(
-                            &mut visitor.zip_writer,
-                            conffiles_path.as_str(),
-                            &mut &visitor.conffiles[..],
-                        );
-                    }
+            for (package_name, package_url, deb_path) in &bootstrap_packages {
+                let result = File::open(deb_path)
+                    .map_err(DebError::from)
+                    .and_then(|file| visit_files(&mut BufReader::new(file), &mut visitor));
+                if let Err(error) = result {
+                    // Never leave a zip containing a corrupted package behind.
+                    let _ = std::fs::remove_file(&output_zip_path);
+                    panic!("Error reading {}: {}", package_url, error);
                 }
+                visitor.write_info_files(package_name);
             }
 
             write_zip_file(
@@ -0,0 +1,33 @@
+use crate::deb_builder::build_deb;
+use crate::deb_file::Compression;
+use std::fs;
+use std::path::Path;
+use std::process::exit;
+
+pub fn build(directory: &str, output: &str, compression: Compression) {
+    let mut deb = Vec::new();
+    let control = match build_deb(Path::new(directory), compression, &mut deb) {
+        Ok(control) => control,
+        Err(e) => {
+            eprintln!("Error building package from '{}': {}", directory, e);
+            exit(1);
+        }
+    };
+
+    let mut output_path = Path::new(output).to_path_buf();
+    if output_path.is_dir() {
+        // Like dpkg-deb, name the file after the package and leave out any epoch.
+        let version = &control["Version"];
+        let version = version.split_once(':').map_or(version, |(_, v)| v);
+        output_path.push(format!(
+            "{}_{}_{}.deb",
+            &control["Package"], version, &control["Architecture"]
+        ));
+    }
+
+    if let Err(e) = fs::write(&output_path, deb) {
+        eprintln!("Error writing {:?}: {}", output_path, e);
+        exit(1);
+    }
+    println!("Created {:?}", output_path);
+}
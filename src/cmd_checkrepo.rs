@@ -1,13 +1,18 @@
-use crate::deb_file;
+use crate::apt_repo::{fetch_universe, RepoSource, SourcePriority};
+use crate::deb822::Paragraph;
+use crate::deb_file::{self, DebError, Md5sumsVerifier};
+use crate::deb_resolver::check_dependencies;
+use crate::deb_structure;
 use std::collections::HashMap;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read, Seek};
 use std::path;
 use std::process;
 
 struct CheckRepoVisitor {
     current_package_name: String,
     files_to_package: HashMap<String, String>,
+    md5sums_verifier: Md5sumsVerifier,
 }
 
 impl CheckRepoVisitor {
@@ -15,18 +20,32 @@ impl CheckRepoVisitor {
         CheckRepoVisitor {
             current_package_name: String::from(""),
             files_to_package: HashMap::new(),
+            md5sums_verifier: Md5sumsVerifier::new(),
         }
     }
 }
 
 impl deb_file::DebVisitor for CheckRepoVisitor {
-    fn visit_control(&mut self, fields: HashMap<String, String>) {
-        self.current_package_name = fields["Package"].clone();
+    fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError> {
+        self.current_package_name = control
+            .get("Package")
+            .ok_or_else(|| DebError::MalformedControl("missing Package field".to_string()))?
+            .to_string();
+        Ok(())
     }
 
-    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) {
-        let path = String::from(file.path().unwrap().to_str().unwrap());
+    fn visit_conffiles(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.md5sums_verifier.visit_conffiles(file)
+    }
+
+    fn visit_md5sums(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.md5sums_verifier.visit_md5sums(file)
+    }
+
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        let path = file.path()?.to_string_lossy().into_owned();
         let path_copy = path.clone();
+        self.md5sums_verifier.visit_file(file)?;
 
         let entry_type = file.header().entry_type();
         if entry_type == tar::EntryType::Link {
@@ -34,11 +53,11 @@ impl deb_file::DebVisitor for CheckRepoVisitor {
                 "Invalid link {} in package {}",
                 path, self.current_package_name
             );
-            return;
+            return Ok(());
         }
 
         if !(entry_type == tar::EntryType::Regular || entry_type == tar::EntryType::Symlink) {
-            return;
+            return Ok(());
         }
 
         match self
@@ -53,6 +72,7 @@ impl deb_file::DebVisitor for CheckRepoVisitor {
                 );
             }
         }
+        Ok(())
     }
 }
 
@@ -77,8 +97,52 @@ pub fn check(path: &str) {
                 let path = entry.path();
                 let mut deb_file = fs::File::open(entry.path()).unwrap();
                 println!("Checking {:?}", path);
-                deb_file::visit_files(&mut deb_file, &mut visitor);
+                match deb_structure::validate_structure(&mut io::BufReader::new(&deb_file)) {
+                    Ok(problems) => {
+                        for problem in problems {
+                            println!("Invalid structure of {:?}: {}", path, problem);
+                        }
+                    }
+                    Err(e) => println!("Error reading {:?}: {}", path, e),
+                }
+                deb_file.rewind().unwrap();
+                if let Err(e) = deb_file::visit_files(&mut deb_file, &mut visitor) {
+                    println!("Invalid deb file {:?}: {}", path, e);
+                }
+                let md5sums_report = visitor.md5sums_verifier.finish();
+                if !md5sums_report.is_ok() {
+                    print!("Invalid md5sums in {:?}:\n{}", path, md5sums_report);
+                }
             }
         }
     }
 }
+
+/// Check that the dependencies of the packages in some repositories can be
+/// satisfied on each architecture the repositories are used for, exiting
+/// with an error if any cannot.
+pub fn check_sources(sources: &[RepoSource], priority: SourcePriority) {
+    let mut problem_count = 0;
+    for arch in &["arm", "aarch64", "i686", "x86_64"] {
+        if !sources.iter().any(|source| source.supports_arch(arch)) {
+            continue;
+        }
+        let arch_sources: Vec<_> = sources
+            .iter()
+            .filter(|source| source.supports_arch(arch))
+            .flat_map(|source| vec![source.with_arch(arch), source.with_arch("all")])
+            .collect();
+        let index = fetch_universe(&arch_sources, priority).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        for problem in check_dependencies(&index, arch) {
+            eprintln!("{}: {}", arch, problem);
+            problem_count += 1;
+        }
+    }
+    if problem_count != 0 {
+        eprintln!("{} dependency problems found", problem_count);
+        process::exit(1);
+    }
+}
@@ -1,24 +1,334 @@
-use crate::deb_file;
-use std::collections::{BTreeMap, HashMap};
+use crate::deb_file::{self, Md5sumsReport};
+use crate::deb_package::{DebFile, DebPackage, FileType, ReadOptions};
+use serde_json::{json, Map, Value};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, Write};
 
-pub fn print(file_path: &str) {
+/// What to show about a deb file. The control file is shown as text if no other
+/// view is selected, and is always included in JSON output.
+pub struct PrintOptions {
+    pub list: bool,
+    pub conffiles: bool,
+    pub scripts: bool,
+    pub json: bool,
+}
+
+fn file_to_json(file: &DebFile) -> Value {
+    json!({
+        "mode": mode_string(file.file_type, file.mode),
+        "owner": file.owner,
+        "group": file.group,
+        "size": file.size,
+        "mtime": file.mtime,
+        "path": file.path,
+        "link_target": file.link_target,
+    })
+}
+
+/// Format a file type and mode like `ls -l` and `dpkg -c`, such as `drwxr-xr-x`.
+fn mode_string(file_type: FileType, mode: u32) -> String {
+    let type_char = match file_type {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::HardLink => 'h',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        FileType::Regular | FileType::Other => '-',
+    };
+    let mut result = String::with_capacity(10);
+    result.push(type_char);
+    for (shift, special_bit, special_char) in
+        &[(6, 0o4000, 's'), (3, 0o2000, 's'), (0, 0o1000, 't')]
+    {
+        let bits = (mode >> shift) & 0o7;
+        result.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        result.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        result.push(match (bits & 0o1 != 0, mode & special_bit != 0) {
+            (true, true) => *special_char,
+            (false, true) => special_char.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    result
+}
+
+/// Format a modification time in the local time zone, like `dpkg -c`.
+fn format_mtime(mtime: u64) -> String {
+    chrono::DateTime::from_timestamp(mtime as i64, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+fn print_text(
+    package: &DebPackage,
+    options: &PrintOptions,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    if !(options.list || options.conffiles || options.scripts) {
+        write!(out, "{}", package.control)?;
+    }
+    if options.conffiles {
+        for conffile in &package.conffiles {
+            writeln!(out, "{}", conffile)?;
+        }
+    }
+    if options.scripts {
+        for (script, contents) in &package.maintainer_scripts {
+            let contents = String::from_utf8_lossy(contents);
+            writeln!(out, "==> {} <==", script.name())?;
+            write!(out, "{}", contents)?;
+            if !contents.ends_with('\n') {
+                writeln!(out)?;
+            }
+        }
+    }
+    if options.list {
+        for file in &package.files {
+            let link = match &file.link_target {
+                Some(target) if file.file_type == FileType::HardLink => {
+                    format!(" link to {}", target)
+                }
+                Some(target) => format!(" -> {}", target),
+                None => String::new(),
+            };
+            writeln!(
+                out,
+                "{} {}/{} {:>9} {} {}{}",
+                mode_string(file.file_type, file.mode),
+                file.owner,
+                file.group,
+                file.size,
+                format_mtime(file.mtime),
+                file.path,
+                link
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn print_json(
+    package: &DebPackage,
+    options: &PrintOptions,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut output = Map::new();
+    let control: Map<String, Value> = package
+        .control
+        .fields()
+        .map(|(name, value)| (name.to_string(), Value::from(value)))
+        .collect();
+    output.insert("control".to_string(), Value::Object(control));
+    if options.conffiles {
+        output.insert("conffiles".to_string(), json!(package.conffiles));
+    }
+    if options.scripts {
+        let scripts: Map<String, Value> = package
+            .maintainer_scripts
+            .iter()
+            .map(|(script, contents)| {
+                let contents = String::from_utf8_lossy(contents).into_owned();
+                (script.name().to_string(), Value::from(contents))
+            })
+            .collect();
+        output.insert("scripts".to_string(), Value::Object(scripts));
+    }
+    if options.list {
+        let files = package.files.iter().map(file_to_json).collect();
+        output.insert("files".to_string(), Value::Array(files));
+    }
+    writeln!(out, "{}", Value::Object(output))
+}
+
+pub fn print(file_path: &str, options: &PrintOptions) {
     let mut deb_file = File::open(file_path).unwrap();
 
-    struct PrintControlVisitor {}
-    impl deb_file::DebVisitor for PrintControlVisitor {
-        fn visit_control(&mut self, fields: HashMap<String, String>) {
-            let sorted_map: BTreeMap<_, _> = fields.iter().collect();
-            for (key, value) in &sorted_map {
-                println!("{}: {}", key, value);
+    let read_options = ReadOptions {
+        file_contents: false,
+    };
+    let package = match DebPackage::read_with_options(&mut deb_file, read_options) {
+        Ok(package) => package,
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = if options.json {
+        print_json(&package, options, &mut out)
+    } else {
+        print_text(&package, options, &mut out)
+    };
+    result.expect("Error writing to stdout");
+}
+
+fn md5sums_report_to_json(report: &Md5sumsReport) -> Value {
+    json!({
+        "ok": report.is_ok(),
+        "has_md5sums": report.has_md5sums,
+        "missing": report.missing,
+        "extra": report.extra,
+        "mismatched": report.mismatched,
+    })
+}
+
+pub fn verify(file_path: &str, json: bool) {
+    let mut deb_file = File::open(file_path).unwrap();
+    match deb_file::verify_md5sums(&mut deb_file) {
+        Ok(report) => {
+            if json {
+                println!("{}", md5sums_report_to_json(&report));
+            } else if report.is_ok() {
+                println!("{}: OK", file_path);
+            } else {
+                print!("{}", report);
+            }
+            if !report.is_ok() {
+                std::process::exit(1);
             }
         }
+        Err(e) => {
+            eprintln!("Error reading {}: {}", file_path, e);
+            std::process::exit(1);
+        }
+    }
+}
 
-        fn visit_file(&mut self, _: &mut tar::Entry<impl Read>) {
-            // Ignore
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deb_builder::build_deb;
+    use crate::deb_file::Compression;
+    use std::fs;
+
+    fn read_test_package() -> DebPackage {
+        let staging_dir = tempfile::tempdir().unwrap();
+        let staging_path = staging_dir.path();
+        fs::create_dir_all(staging_path.join("DEBIAN")).unwrap();
+        fs::create_dir_all(staging_path.join("etc")).unwrap();
+        fs::write(
+            staging_path.join("DEBIAN/control"),
+            "Package: a\nVersion: 1\nArchitecture: all\n",
+        )
+        .unwrap();
+        fs::write(staging_path.join("DEBIAN/conffiles"), "/etc/a.conf\n").unwrap();
+        fs::write(staging_path.join("DEBIAN/preinst"), "#!/bin/sh").unwrap();
+        fs::write(staging_path.join("DEBIAN/postrm"), "#!/bin/sh\n").unwrap();
+        fs::write(staging_path.join("etc/a.conf"), "a").unwrap();
+        std::os::unix::fs::symlink("a.conf", staging_path.join("etc/b.conf")).unwrap();
+        let mut deb = Vec::new();
+        build_deb(staging_path, Compression::Zstd, &mut deb).unwrap();
+
+        let options = ReadOptions {
+            file_contents: false,
+        };
+        let mut package = DebPackage::read_with_options(&mut &deb[..], options).unwrap();
+        // The times come from the staging directory, so fix them for the listing.
+        for file in &mut package.files {
+            file.mtime = 1_600_000_000;
         }
+        package
+    }
+
+    fn text_output(package: &DebPackage, options: &PrintOptions) -> String {
+        let mut output = Vec::new();
+        if options.json {
+            print_json(package, options, &mut output).unwrap();
+        } else {
+            print_text(package, options, &mut output).unwrap();
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    fn options(list: bool, conffiles: bool, scripts: bool, json: bool) -> PrintOptions {
+        PrintOptions {
+            list,
+            conffiles,
+            scripts,
+            json,
+        }
+    }
+
+    #[test]
+    fn test_print_text() {
+        // Times are listed in the local time zone, so pin it to UTC+9.
+        std::env::set_var("TZ", "JST-9");
+        let package = read_test_package();
+        assert_eq!(
+            "Package: a\nVersion: 1\nArchitecture: all\nInstalled-Size: 3\n",
+            text_output(&package, &options(false, false, false, false))
+        );
+        assert_eq!(
+            "/etc/a.conf\n",
+            text_output(&package, &options(false, true, false, false))
+        );
+        assert_eq!(
+            "==> postrm <==\n#!/bin/sh\n==> preinst <==\n#!/bin/sh\n",
+            text_output(&package, &options(false, false, true, false))
+        );
+        assert_eq!(
+            "drwxr-xr-x root/root         0 2020-09-13 21:26 ./\n\
+             drwxr-xr-x root/root         0 2020-09-13 21:26 ./etc/\n\
+             -rw-r--r-- root/root         1 2020-09-13 21:26 ./etc/a.conf\n\
+             lrwxrwxrwx root/root         0 2020-09-13 21:26 ./etc/b.conf -> a.conf\n",
+            text_output(&package, &options(true, false, false, false))
+        );
+    }
+
+    #[test]
+    fn test_print_json() {
+        let package = read_test_package();
+        let parse =
+            |options| -> Value { serde_json::from_str(&text_output(&package, &options)).unwrap() };
+        assert_eq!(
+            json!({
+                "control": {
+                    "Package": "a",
+                    "Version": "1",
+                    "Architecture": "all",
+                    "Installed-Size": "3",
+                }
+            }),
+            parse(options(false, false, false, true))
+        );
+
+        let output = parse(options(true, true, true, true));
+        assert_eq!(json!(["/etc/a.conf"]), output["conffiles"]);
+        assert_eq!(
+            json!({"preinst": "#!/bin/sh", "postrm": "#!/bin/sh\n"}),
+            output["scripts"]
+        );
+        let files = output["files"].as_array().unwrap();
+        assert_eq!(4, files.len());
+        assert_eq!(
+            json!({
+                "mode": "-rw-r--r--",
+                "owner": "root",
+                "group": "root",
+                "size": 1,
+                "mtime": 1_600_000_000,
+                "path": "./etc/a.conf",
+                "link_target": null,
+            }),
+            files[2]
+        );
+        assert_eq!("a.conf", files[3]["link_target"]);
+    }
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!("drwxr-xr-x", mode_string(FileType::Directory, 0o755));
+        assert_eq!("-rw-r--r--", mode_string(FileType::Regular, 0o644));
+        assert_eq!("lrwxrwxrwx", mode_string(FileType::Symlink, 0o777));
+        assert_eq!("-rwsr-xr-x", mode_string(FileType::Regular, 0o4755));
+        assert_eq!("-rw-r-Sr-T", mode_string(FileType::Regular, 0o3644));
     }
-    let mut visitor = PrintControlVisitor {};
-    deb_file::visit_files(&mut deb_file, &mut visitor);
 }
@@ -0,0 +1,306 @@
+use crate::deb822::Paragraph;
+use crate::deb_file::{visit_files, DebError, DebVisitor, MaintainerScript};
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+
+/// Visitor unpacking data.tar into a directory like `dpkg -x`, and optionally
+/// control.tar into its DEBIAN subdirectory like `dpkg -e`, so that the result
+/// can be rebuilt with `build-deb`.
+pub struct ExtractVisitor {
+    destination: PathBuf,
+    control_destination: Option<PathBuf>,
+}
+
+/// Check that a path from the package stays inside the destination directory.
+fn check_path(path: &Path) -> Result<(), DebError> {
+    let unsafe_component = path
+        .components()
+        .any(|component| !matches!(component, Component::CurDir | Component::Normal(_)));
+    if unsafe_component {
+        Err(DebError::UnsafePath(path.to_string_lossy().into_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+impl ExtractVisitor {
+    pub fn new(destination: &Path, extract_control: bool) -> ExtractVisitor {
+        ExtractVisitor {
+            destination: destination.to_path_buf(),
+            control_destination: if extract_control {
+                Some(destination.join("DEBIAN"))
+            } else {
+                None
+            },
+        }
+    }
+
+    fn extract_control_file(
+        &self,
+        name: &str,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        if let Some(control_destination) = &self.control_destination {
+            check_path(Path::new(name))?;
+            if Path::new(name).components().count() != 1 {
+                return Err(DebError::UnsafePath(name.to_string()));
+            }
+            file.set_preserve_permissions(true);
+            file.unpack(control_destination.join(name))?;
+        }
+        Ok(())
+    }
+}
+
+impl DebVisitor for ExtractVisitor {
+    fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError> {
+        if let Some(control_destination) = &self.control_destination {
+            let mut file = File::create(control_destination.join("control"))?;
+            file.write_all(control.to_string().as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn visit_conffiles(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.extract_control_file("conffiles", file)
+    }
+
+    fn visit_maintainer_script(
+        &mut self,
+        script: MaintainerScript,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        self.extract_control_file(script.name(), file)
+    }
+
+    fn visit_md5sums(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.extract_control_file("md5sums", file)
+    }
+
+    fn visit_triggers(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.extract_control_file("triggers", file)
+    }
+
+    fn visit_shlibs(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.extract_control_file("shlibs", file)
+    }
+
+    fn visit_other_control_file(
+        &mut self,
+        name: &str,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        self.extract_control_file(name, file)
+    }
+
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        check_path(&file.path()?)?;
+        if file.header().entry_type().is_hard_link() {
+            if let Some(target) = file.link_name()? {
+                check_path(&target)?;
+            }
+        }
+        file.set_preserve_permissions(true);
+        file.set_preserve_mtime(true);
+        // Also refuses to write through symlinks pointing outside the destination.
+        file.unpack_in(&self.destination)?;
+        Ok(())
+    }
+}
+
+/// Extract a deb file into a directory, including the control files in a
+/// DEBIAN subdirectory if `extract_control` is set.
+pub fn extract_deb(
+    reader: &mut impl Read,
+    destination: &Path,
+    extract_control: bool,
+) -> Result<(), DebError> {
+    let mut visitor = ExtractVisitor::new(destination, extract_control);
+    fs::create_dir_all(destination)?;
+    if let Some(control_destination) = &visitor.control_destination {
+        fs::create_dir_all(control_destination)?;
+    }
+    visit_files(reader, &mut visitor)
+}
+
+pub fn extract(file_path: &str, directory: &str, extract_control: bool) {
+    let mut deb_file = File::open(file_path).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", file_path, e);
+        exit(1);
+    });
+    if let Err(e) = extract_deb(&mut deb_file, Path::new(directory), extract_control) {
+        eprintln!("Error extracting {}: {}", file_path, e);
+        exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deb_builder::{build_deb, write_deb};
+    use crate::deb_file::Compression;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+
+    /// Check that two directories have the same entries, with the same
+    /// contents, permissions and symlink targets.
+    fn assert_same_tree(expected: &Path, actual: &Path) {
+        let entries = |root: &Path| -> Vec<_> {
+            walkdir::WalkDir::new(root)
+                .min_depth(1)
+                .sort_by_file_name()
+                .into_iter()
+                .map(|entry| entry.unwrap().path().strip_prefix(root).unwrap().to_owned())
+                .collect()
+        };
+        assert_eq!(entries(expected), entries(actual));
+        for relative_path in entries(expected) {
+            let (expected_path, actual_path) =
+                (expected.join(&relative_path), actual.join(&relative_path));
+            let expected_metadata = fs::symlink_metadata(&expected_path).unwrap();
+            let actual_metadata = fs::symlink_metadata(&actual_path).unwrap();
+            let file_type = expected_metadata.file_type();
+            assert_eq!(
+                file_type,
+                actual_metadata.file_type(),
+                "{:?}",
+                relative_path
+            );
+            if file_type.is_symlink() {
+                assert_eq!(
+                    fs::read_link(&expected_path).unwrap(),
+                    fs::read_link(&actual_path).unwrap(),
+                    "{:?}",
+                    relative_path
+                );
+                continue;
+            }
+            assert_eq!(
+                expected_metadata.mode() & 0o7777,
+                actual_metadata.mode() & 0o7777,
+                "{:?}",
+                relative_path
+            );
+            if file_type.is_file() {
+                assert_eq!(
+                    fs::read(&expected_path).unwrap(),
+                    fs::read(&actual_path).unwrap(),
+                    "{:?}",
+                    relative_path
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_extract_round_trip() {
+        let staging_dir = tempfile::tempdir().unwrap();
+        let staging_path = staging_dir.path();
+        fs::create_dir_all(staging_path.join("DEBIAN")).unwrap();
+        fs::create_dir_all(staging_path.join("usr/bin")).unwrap();
+        fs::write(
+            staging_path.join("DEBIAN/control"),
+            "Package: a\nVersion: 1\nArchitecture: all\n",
+        )
+        .unwrap();
+        fs::write(staging_path.join("DEBIAN/postinst"), "#!/bin/sh\n").unwrap();
+        fs::write(staging_path.join("usr/bin/a"), "#!/bin/sh\necho a\n").unwrap();
+        fs::write(staging_path.join("usr/bin/d"), [0, 1, 2, 255]).unwrap();
+        fs::set_permissions(
+            staging_path.join("usr/bin/a"),
+            fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        fs::hard_link(
+            staging_path.join("usr/bin/a"),
+            staging_path.join("usr/bin/b"),
+        )
+        .unwrap();
+        symlink("a", staging_path.join("usr/bin/c")).unwrap();
+        let mut deb = Vec::new();
+        build_deb(staging_path, Compression::Gzip, &mut deb).unwrap();
+
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path();
+        extract_deb(&mut &deb[..], output_path, true).unwrap();
+
+        let metadata = fs::metadata(output_path.join("usr/bin/a")).unwrap();
+        assert_eq!(0o750, metadata.mode() & 0o777);
+        assert_eq!(
+            fs::metadata(staging_path.join("usr/bin/a"))
+                .unwrap()
+                .mtime(),
+            metadata.mtime()
+        );
+        assert_eq!(2, metadata.nlink());
+        assert_eq!(
+            Path::new("a"),
+            fs::read_link(output_path.join("usr/bin/c")).unwrap()
+        );
+        assert_eq!(
+            0o755,
+            fs::metadata(output_path.join("DEBIAN/postinst"))
+                .unwrap()
+                .mode()
+                & 0o777
+        );
+
+        assert_same_tree(&staging_path.join("usr"), &output_path.join("usr"));
+        assert_eq!(
+            "#!/bin/sh\n",
+            fs::read_to_string(output_path.join("DEBIAN/postinst")).unwrap()
+        );
+        // Building adds Installed-Size to the control file.
+        assert_eq!(
+            "Package: a\nVersion: 1\nArchitecture: all\nInstalled-Size: 5\n",
+            fs::read_to_string(output_path.join("DEBIAN/control")).unwrap()
+        );
+
+        // The extracted directory can be rebuilt into the same package.
+        let mut rebuilt_deb = Vec::new();
+        build_deb(output_path, Compression::Gzip, &mut rebuilt_deb).unwrap();
+        assert_eq!(deb, rebuilt_deb);
+    }
+
+    #[test]
+    fn test_extract_refuses_unsafe_paths() {
+        for path in &["../evil", "/evil", "./a/../../evil"] {
+            let mut data_tar = Vec::new();
+            {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(0);
+                header.set_mode(0o644);
+                header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+                header.set_cksum();
+                let mut builder = tar::Builder::new(&mut data_tar);
+                builder.append(&header, &[][..]).unwrap();
+                builder.finish().unwrap();
+            }
+            let mut control_tar = Vec::new();
+            {
+                let control = b"Package: a\nVersion: 1\nArchitecture: all\n";
+                let mut header = tar::Header::new_gnu();
+                header.set_size(control.len() as u64);
+                header.set_mode(0o644);
+                let mut builder = tar::Builder::new(&mut control_tar);
+                builder
+                    .append_data(&mut header, "control", &control[..])
+                    .unwrap();
+                builder.finish().unwrap();
+            }
+            let mut deb = Vec::new();
+            write_deb(&mut deb, Compression::None, &control_tar, &data_tar).unwrap();
+
+            let output_dir = tempfile::tempdir().unwrap();
+            let destination = output_dir.path().join("destination");
+            let result = extract_deb(&mut &deb[..], &destination, false);
+            assert!(
+                matches!(&result, Err(DebError::UnsafePath(p)) if p == path),
+                "{:?}",
+                result
+            );
+            assert!(!output_dir.path().join("evil").exists());
+        }
+    }
+}
@@ -0,0 +1,488 @@
+use crate::apt_repo::{replace_file, sha1_hex, sha256_hex, write_release, ReleaseOptions};
+use crate::deb822::{Paragraph, Paragraphs};
+use crate::deb_file::{visit_files, Compression, DebError, DebVisitor};
+use crate::deb_version::DebVersion;
+use crate::openpgp::SigningKey;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use walkdir::WalkDir;
+
+struct ControlVisitor {
+    control: Option<Paragraph>,
+}
+
+impl DebVisitor for ControlVisitor {
+    fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError> {
+        self.control = Some(control);
+        Ok(())
+    }
+
+    fn visit_file(&mut self, _file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        Ok(())
+    }
+}
+
+/// Create the Packages stanza of a deb file: its control fields followed by
+/// where to find it and its size and hashes.
+pub fn package_stanza(repo_dir: &Path, filename: &str) -> Result<Paragraph, DebError> {
+    let contents = fs::read(repo_dir.join(filename))?;
+    let mut visitor = ControlVisitor { control: None };
+    visit_files(&mut &contents[..], &mut visitor)?;
+    let mut stanza = visitor
+        .control
+        .ok_or_else(|| DebError::MalformedControl("missing control file".to_string()))?;
+    for field in &["Package", "Version", "Architecture"] {
+        if !stanza.contains(field) {
+            return Err(DebError::MalformedControl(format!(
+                "missing {} field",
+                field
+            )));
+        }
+    }
+    stanza.set("Filename", filename);
+    stanza.set("Size", &contents.len().to_string());
+    stanza.set("MD5sum", &format!("{:x}", md5::compute(&contents)));
+    stanza.set("SHA1", &sha1_hex(&contents));
+    stanza.set("SHA256", &sha256_hex(&contents));
+    Ok(stanza)
+}
+
+/// What identifies the exact file a stanza was created from. Unlike the
+/// modification time, the change time cannot be set back, so a file which
+/// is replaced or rewritten always gets a new identity.
+#[derive(Debug, PartialEq, Eq)]
+struct FileIdentity {
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    inode: u64,
+}
+
+impl FileIdentity {
+    fn of(path: &Path) -> io::Result<FileIdentity> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileIdentity {
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            inode: metadata.ino(),
+        })
+    }
+
+    fn parse(line: &str) -> Option<(String, FileIdentity)> {
+        fn number<T: std::str::FromStr>(parts: &[&str], i: usize) -> Option<T> {
+            parts.get(i)?.parse().ok()
+        }
+        let parts: Vec<_> = line.split('\t').collect();
+        match parts[..] {
+            [filename, ..] if parts.len() == 7 => Some((
+                filename.to_string(),
+                FileIdentity {
+                    size: number(&parts, 1)?,
+                    mtime: (number(&parts, 2)?, number(&parts, 3)?),
+                    ctime: (number(&parts, 4)?, number(&parts, 5)?),
+                    inode: number(&parts, 6)?,
+                },
+            )),
+            _ => None,
+        }
+    }
+
+    fn to_line(&self, filename: &str) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            filename, self.size, self.mtime.0, self.mtime.1, self.ctime.0, self.ctime.1, self.inode
+        )
+    }
+}
+
+/// The file recording the identity of the deb files indexed for a component,
+/// kept outside of `dists/` so that it is not published.
+fn identity_cache_path(repo_dir: &Path, suite: &str, component: &str) -> PathBuf {
+    repo_dir.join(".index-cache").join(suite).join(component)
+}
+
+fn read_identity_cache(path: &Path) -> HashMap<String, FileIdentity> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return HashMap::new(),
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| FileIdentity::parse(&line))
+        .collect()
+}
+
+/// A stanza from a previously generated Packages file, and the identity of
+/// the deb file it was created from, if known.
+struct PreviousEntry {
+    stanza: Paragraph,
+    identity: Option<FileIdentity>,
+}
+
+/// Read the stanzas of all existing Packages files under a component
+/// directory, by Filename.
+fn read_previous_entries(
+    component_dir: &Path,
+    mut identities: HashMap<String, FileIdentity>,
+) -> HashMap<String, PreviousEntry> {
+    let mut result = HashMap::new();
+    let binary_dirs = match fs::read_dir(component_dir) {
+        Ok(entries) => entries,
+        Err(_) => return result,
+    };
+    for binary_dir in binary_dirs.flatten() {
+        let packages_path = binary_dir.path().join("Packages");
+        let file = match File::open(&packages_path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        for stanza in Paragraphs::new(BufReader::new(file)) {
+            let stanza = match stanza {
+                Ok(stanza) => stanza,
+                Err(e) => {
+                    eprintln!("Ignoring invalid {}: {}", packages_path.display(), e);
+                    break;
+                }
+            };
+            if let Some(filename) = stanza.get("Filename").map(String::from) {
+                let identity = identities.remove(&filename);
+                result.insert(filename, PreviousEntry { stanza, identity });
+            }
+        }
+    }
+    result
+}
+
+/// If the deb file is provably the one a previous stanza was created from, so
+/// the stanza can be reused without reading and hashing the file again.
+fn is_unchanged(identity: &FileIdentity, previous: &PreviousEntry) -> bool {
+    previous.identity.as_ref() == Some(identity)
+        && previous.stanza.get("Size") == Some(identity.size.to_string().as_str())
+}
+
+pub struct IndexSummary {
+    /// Number of packages indexed per architecture.
+    pub packages: BTreeMap<String, usize>,
+    /// Number of stanzas reused from the previous index.
+    pub reused: usize,
+    /// Architectures whose indexes were removed as they no longer have any
+    /// packages.
+    pub removed: Vec<String>,
+    /// Deb files which could not be read, with the reason.
+    pub skipped: Vec<(String, DebError)>,
+}
+
+/// Index the deb files under `<repo_dir>/<pool>`, writing Packages,
+/// Packages.gz and Packages.xz files to
+/// `<repo_dir>/dists/<suite>/<component>/binary-<arch>/`.
+pub fn write_indexes(
+    repo_dir: &Path,
+    pool: &str,
+    suite: &str,
+    component: &str,
+) -> io::Result<IndexSummary> {
+    let component_dir = repo_dir.join("dists").join(suite).join(component);
+    let cache_path = identity_cache_path(repo_dir, suite, component);
+    let previous_entries = read_previous_entries(&component_dir, read_identity_cache(&cache_path));
+    let mut identity_cache = String::new();
+    let mut summary = IndexSummary {
+        packages: BTreeMap::new(),
+        reused: 0,
+        removed: Vec::new(),
+        skipped: Vec::new(),
+    };
+
+    let mut stanzas_by_arch: BTreeMap<String, Vec<Paragraph>> = BTreeMap::new();
+    for entry in WalkDir::new(repo_dir.join(pool)).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type().is_file() || path.extension().is_none_or(|e| e != "deb") {
+            continue;
+        }
+        let filename = path
+            .strip_prefix(repo_dir)
+            .expect("Walked path is inside the repository")
+            .to_string_lossy()
+            .into_owned();
+
+        let identity = FileIdentity::of(path)?;
+        let stanza = match previous_entries.get(&filename) {
+            Some(previous) if is_unchanged(&identity, previous) => {
+                summary.reused += 1;
+                previous.stanza.clone()
+            }
+            _ => match package_stanza(repo_dir, &filename) {
+                Ok(stanza) => stanza,
+                Err(e) => {
+                    summary.skipped.push((filename, e));
+                    continue;
+                }
+            },
+        };
+        // Hashing reads the file after its identity was taken, so a file
+        // changed in between is hashed again next time.
+        identity_cache += &identity.to_line(&filename);
+        stanzas_by_arch
+            .entry(stanza["Architecture"].to_string())
+            .or_default()
+            .push(stanza);
+    }
+
+    for (arch, stanzas) in &mut stanzas_by_arch {
+        stanzas.sort_by_cached_key(|stanza| {
+            (
+                stanza["Package"].to_string(),
+                stanza["Version"].parse::<DebVersion>().ok(),
+                stanza["Filename"].to_string(),
+            )
+        });
+        let packages = stanzas
+            .iter()
+            .map(|stanza| {
+                // A stanza reused from a Packages file without a final
+                // newline still has to end its line.
+                let mut text = stanza.to_string();
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                text
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let binary_dir = component_dir.join(format!("binary-{}", arch));
+        fs::create_dir_all(&binary_dir)?;
+        for &compression in &[Compression::None, Compression::Gzip, Compression::Xz] {
+            let path = binary_dir.join(format!("Packages{}", compression.extension()));
+            replace_file(&path, &compression.compress(packages.as_bytes())?)?;
+        }
+        summary.packages.insert(arch.clone(), stanzas.len());
+    }
+
+    // Remove the indexes of architectures without packages, so that they are
+    // not published with stale contents.
+    if let Ok(entries) = fs::read_dir(&component_dir) {
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(arch) = name.strip_prefix("binary-") {
+                if !stanzas_by_arch.contains_key(arch) && entry.file_type()?.is_dir() {
+                    fs::remove_dir_all(entry.path())?;
+                    summary.removed.push(arch.to_string());
+                }
+            }
+        }
+    }
+    summary.removed.sort();
+
+    fs::create_dir_all(cache_path.parent().expect("Cache path has a directory"))?;
+    replace_file(&cache_path, identity_cache.as_bytes())?;
+    Ok(summary)
+}
+
+pub fn index(
+    directory: &str,
+    pool: &str,
+    component: &str,
+    release_options: &ReleaseOptions,
+    signing_key: Option<&Path>,
+) {
+    let signing_key = signing_key.map(|path| {
+        SigningKey::read(path).unwrap_or_else(|e| {
+            eprintln!("Error reading signing key: {}", e);
+            exit(1);
+        })
+    });
+    let repo_dir = Path::new(directory);
+    let summary = match write_indexes(repo_dir, pool, &release_options.suite, component) {
+        Ok(summary) => summary,
+        Err(e) => {
+            eprintln!("Error indexing {}: {}", directory, e);
+            exit(1);
+        }
+    };
+    if let Err(e) = write_release(repo_dir, release_options, signing_key.as_ref()) {
+        eprintln!("Error writing Release file: {}", e);
+        exit(1);
+    }
+    for (filename, error) in &summary.skipped {
+        eprintln!("Skipping invalid deb file {}: {}", filename, error);
+    }
+    for (arch, count) in &summary.packages {
+        println!("{}: {} packages", arch, count);
+    }
+    for arch in &summary.removed {
+        println!("{}: removed, no packages left", arch);
+    }
+    println!("Reused {} unchanged entries", summary.reused);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deb_builder::build_deb;
+
+    fn write_deb(repo_dir: &Path, name: &str, arch: &str) {
+        write_deb_version(repo_dir, name, "1.0", arch);
+    }
+
+    fn write_deb_version(repo_dir: &Path, name: &str, version: &str, arch: &str) {
+        let staging_dir = tempfile::tempdir().unwrap();
+        fs::create_dir(staging_dir.path().join("DEBIAN")).unwrap();
+        fs::write(
+            staging_dir.path().join("DEBIAN/control"),
+            format!(
+                "Package: {}\nVersion: {}\nArchitecture: {}\nDescription: Test\n",
+                name, version, arch
+            ),
+        )
+        .unwrap();
+        let mut deb = Vec::new();
+        build_deb(staging_dir.path(), Compression::Gzip, &mut deb).unwrap();
+        let pool_dir = repo_dir.join("pool/main");
+        fs::create_dir_all(&pool_dir).unwrap();
+        fs::write(
+            pool_dir.join(format!("{}_{}_{}.deb", name, version, arch)),
+            deb,
+        )
+        .unwrap();
+    }
+
+    fn read_packages(repo_dir: &Path, arch: &str) -> Vec<Paragraph> {
+        let path = repo_dir.join(format!("dists/stable/main/binary-{}/Packages", arch));
+        Paragraphs::new(BufReader::new(File::open(path).unwrap()))
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_write_indexes() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo_path = repo_dir.path();
+        write_deb(repo_path, "b", "aarch64");
+        write_deb(repo_path, "a", "aarch64");
+        write_deb(repo_path, "c", "all");
+        write_deb_version(repo_path, "d", "1.10", "all");
+        write_deb_version(repo_path, "d", "1.9", "all");
+        fs::write(repo_path.join("pool/main/invalid.deb"), "invalid").unwrap();
+
+        let summary = write_indexes(repo_path, "pool", "stable", "main").unwrap();
+        assert_eq!(Some(&2), summary.packages.get("aarch64"));
+        assert_eq!(Some(&3), summary.packages.get("all"));
+        assert_eq!(0, summary.reused);
+        assert_eq!(1, summary.skipped.len());
+
+        let packages = read_packages(repo_path, "aarch64");
+        assert_eq!("a", &packages[0]["Package"]);
+        assert_eq!("b", &packages[1]["Package"]);
+        let deb = fs::read(repo_path.join("pool/main/a_1.0_aarch64.deb")).unwrap();
+        assert_eq!("pool/main/a_1.0_aarch64.deb", &packages[0]["Filename"]);
+        assert_eq!(deb.len().to_string(), packages[0]["Size"]);
+        assert_eq!(sha256_hex(&deb), packages[0]["SHA256"]);
+        assert_eq!(format!("{:x}", md5::compute(&deb)), packages[0]["MD5sum"]);
+        assert_eq!(40, packages[0]["SHA1"].len());
+        let versions: Vec<_> = read_packages(repo_path, "all")
+            .iter()
+            .map(|stanza| stanza["Version"].to_string())
+            .collect();
+        assert_eq!(vec!["1.0", "1.9", "1.10"], versions);
+
+        let binary_dir = repo_path.join("dists/stable/main/binary-aarch64");
+        assert_eq!(3, fs::read_dir(&binary_dir).unwrap().count());
+        assert!(summary.removed.is_empty());
+        let plain = fs::read(binary_dir.join("Packages")).unwrap();
+        for (name, compression) in &[
+            ("Packages.gz", Compression::Gzip),
+            ("Packages.xz", Compression::Xz),
+        ] {
+            let mut decompressed = Vec::new();
+            compression
+                .decompress(File::open(binary_dir.join(name)).unwrap())
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(plain, decompressed);
+        }
+    }
+
+    #[test]
+    fn test_remove_empty_architectures() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo_path = repo_dir.path();
+        write_deb(repo_path, "a", "all");
+        write_deb(repo_path, "b", "arm");
+        write_indexes(repo_path, "pool", "stable", "main").unwrap();
+        let binary_dir = repo_path.join("dists/stable/main/binary-arm");
+        assert!(binary_dir.exists());
+
+        fs::remove_file(repo_path.join("pool/main/b_1.0_arm.deb")).unwrap();
+        let summary = write_indexes(repo_path, "pool", "stable", "main").unwrap();
+        assert_eq!(vec!["arm"], summary.removed);
+        assert!(!binary_dir.exists());
+        assert!(repo_path.join("dists/stable/main/binary-all").exists());
+    }
+
+    #[test]
+    fn test_reuse_unchanged_entries() {
+        let repo_dir = tempfile::tempdir().unwrap();
+        let repo_path = repo_dir.path();
+        write_deb(repo_path, "a", "all");
+        write_deb(repo_path, "b", "all");
+        write_indexes(repo_path, "pool", "stable", "main").unwrap();
+
+        // Mark the stanzas so that reused ones can be told apart.
+        let packages_path = repo_path.join("dists/stable/main/binary-all/Packages");
+        let mark = || {
+            let marked = fs::read_to_string(&packages_path)
+                .unwrap()
+                .replace("Description: Test", "Description: Reused");
+            fs::write(&packages_path, marked).unwrap();
+        };
+        mark();
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write_deb(repo_path, "b", "all");
+
+        let summary = write_indexes(repo_path, "pool", "stable", "main").unwrap();
+        assert_eq!(1, summary.reused);
+        let packages = read_packages(repo_path, "all");
+        assert_eq!("Reused", &packages[0]["Description"]);
+        assert_eq!("Test", &packages[1]["Description"]);
+
+        // A rebuilt deb of the same size with its old modification time, as
+        // left by `cp -p` or `rsync -a`.
+        mark();
+        let deb_path = repo_path.join("pool/main/a_1.0_all.deb");
+        let modified = fs::metadata(&deb_path).unwrap().modified().unwrap();
+        let mut contents = fs::read(&deb_path).unwrap();
+        // The first digit of the modification time of the first ar member.
+        contents[24] = if contents[24] == b'1' { b'2' } else { b'1' };
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(&deb_path, &contents).unwrap();
+        File::options()
+            .write(true)
+            .open(&deb_path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let summary = write_indexes(repo_path, "pool", "stable", "main").unwrap();
+        assert_eq!(1, summary.reused);
+        let packages = read_packages(repo_path, "all");
+        assert_eq!(sha256_hex(&contents), packages[0]["SHA256"]);
+        assert_eq!("Reused", &packages[1]["Description"]);
+
+        // Without a record of the indexed files, every deb is hashed again.
+        mark();
+        fs::remove_dir_all(repo_path.join(".index-cache")).unwrap();
+        let summary = write_indexes(repo_path, "pool", "stable", "main").unwrap();
+        assert_eq!(0, summary.reused);
+        assert!(read_packages(repo_path, "all")
+            .iter()
+            .all(|stanza| &stanza["Description"] == "Test"));
+    }
+}
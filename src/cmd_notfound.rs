@@ -1,4 +1,5 @@
-use crate::deb_file;
+use crate::deb822::Paragraph;
+use crate::deb_file::{self, DebError};
 use std::collections::HashMap;
 use std::fs::{metadata, File, OpenOptions};
 use std::io::{Read, Write};
@@ -34,22 +35,29 @@ impl CommandsNotFoundVisitor {
 }
 
 impl deb_file::DebVisitor for CommandsNotFoundVisitor {
-    fn visit_control(&mut self, fields: HashMap<String, String>) {
-        self.current_arch = fields["Architecture"].to_string();
+    fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError> {
+        let field = |name: &str| {
+            control
+                .get(name)
+                .map(String::from)
+                .ok_or_else(|| DebError::MalformedControl(format!("missing {} field", name)))
+        };
+        self.current_arch = field("Architecture")?;
         self.first_file = true;
-        self.current_package = fields["Package"].clone();
+        self.current_package = field("Package")?;
+        Ok(())
     }
 
-    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) {
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
         let header = file.header();
         if header.entry_type() != tar::EntryType::Regular
             && header.entry_type() != tar::EntryType::Symlink
         {
-            return;
+            return Ok(());
         }
 
-        let pp = file.path().unwrap();
-        let file_path = pp.to_str().unwrap();
+        let pp = file.path()?;
+        let file_path = pp.to_string_lossy();
 
         if let Some(file_name) = file_path.strip_prefix("./data/data/com.termux/files/usr/bin/") {
             if self.first_file {
@@ -66,11 +74,12 @@ impl deb_file::DebVisitor for CommandsNotFoundVisitor {
             let line: &str = &format!("\" {}\",\n", file_name);
             self.write_arch_line(line);
         }
+        Ok(())
     }
 }
 
 pub fn update(repo_dir: String, output_dir: &str) {
-    match metadata(&output_dir) {
+    match metadata(output_dir) {
         Ok(attr) => {
             if !attr.is_dir() {
                 eprintln!("Output dir '{}' is not a directory", output_dir);
@@ -115,7 +124,9 @@ pub fn update(repo_dir: String, output_dir: &str) {
         let entry = entry.unwrap();
         if entry.file_name().to_str().unwrap().ends_with(".deb") {
             let mut deb_file = File::open(entry.path()).unwrap();
-            deb_file::visit_files(&mut deb_file, &mut deb_visitor);
+            if let Err(e) = deb_file::visit_files(&mut deb_file, &mut deb_visitor) {
+                eprintln!("Skipping invalid deb file {:?}: {}", entry.path(), e);
+            }
         }
     }
 }
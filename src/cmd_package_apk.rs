@@ -1,9 +1,9 @@
-use crate::apt_repo::fetch_repo;
-use crate::deb_file::{visit_files, DebVisitor};
-use std::collections::hash_map::RandomState;
-use std::collections::HashMap;
+use crate::apt_repo::{download_packages, fetch_universe, RepoSource, SourcePriority};
+use crate::cmd_bootstraps::print_progress;
+use crate::deb822::Paragraph;
+use crate::deb_file::{visit_files, DebError, DebVisitor};
 use std::fs::{rename, File};
-use std::io::{copy, ErrorKind, Read, Write};
+use std::io::{copy, BufReader, ErrorKind, Read, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::thread;
 
@@ -15,20 +15,21 @@ pub struct CreateApkVisitor {
 }
 
 impl DebVisitor for CreateApkVisitor {
-    fn visit_control(&mut self, _: HashMap<String, String, RandomState>) {
+    fn visit_control(&mut self, _: Paragraph) -> Result<(), DebError> {
         // Do nothing.
+        Ok(())
     }
 
-    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) {
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
         //let file_path_full: String;
         let header = file.header();
         let is_symlink = header.entry_type() == tar::EntryType::Symlink;
         let is_regular = header.entry_type() == tar::EntryType::Regular;
         if !(is_regular || is_symlink) {
-            return;
+            return Ok(());
         }
 
-        let pp = file.path().unwrap();
+        let pp = file.path()?;
         let file_path = pp.to_str().unwrap();
         let relative_path = &file_path[33..];
         //file_path_full = String::from(&file_path[2..]);
@@ -52,10 +53,11 @@ impl DebVisitor for CreateApkVisitor {
             );
 
             let file_path = format!("{}/lib{}.so", self.output_directory, self.counter);
-            let mut output = File::create(file_path).unwrap();
-            copy(file, &mut output).unwrap();
+            let mut output = File::create(file_path)?;
+            copy(file, &mut output)?;
             self.counter += 1;
         }
+        Ok(())
     }
 }
 
@@ -79,7 +81,14 @@ fn create_dir(path: &str) {
     }
 }
 
-pub fn create_apk(package_name: &str, output_dir: &str, install: bool) {
+pub fn create_apk(
+    package_name: &str,
+    output_dir: &str,
+    install: bool,
+    sources: &[RepoSource],
+    priority: SourcePriority,
+    concurrency: usize,
+) {
     create_dir(output_dir);
     create_dir(&format!("{}/app/src/main", output_dir));
     create_dir(&format!("{}/gradle/wrapper", output_dir));
@@ -121,8 +130,35 @@ pub fn create_apk(package_name: &str, output_dir: &str, install: bool) {
         &android_manifest,
     );
 
-    let mut join_handles = Vec::new();
+    let mut packages = Vec::new();
     for arch in &["arm", "aarch64", "i686", "x86_64"] {
+        let arch_sources: Vec<_> = sources
+            .iter()
+            .filter(|s| s.supports_arch(arch))
+            .map(|s| s.with_arch(arch))
+            .collect();
+        let index = fetch_universe(&arch_sources, priority).unwrap_or_else(|e| panic!("{}", e));
+        let package = index
+            .newest(package_name)
+            .unwrap_or_else(|| panic!("Cannot find package '{}'", package_name))
+            .clone();
+        packages.push(package);
+    }
+    let download_dir = tempfile::tempdir().expect("Cannot create download directory");
+    let deb_paths = download_packages(
+        &packages.iter().collect::<Vec<_>>(),
+        download_dir.path(),
+        concurrency,
+        &print_progress,
+    )
+    .unwrap_or_else(|e| panic!("{}", e));
+
+    let mut join_handles = Vec::new();
+    for ((arch, package), deb_path) in ["arm", "aarch64", "i686", "x86_64"]
+        .iter()
+        .zip(packages)
+        .zip(deb_paths)
+    {
         // x86', 'x86_64', 'armeabi-v7a', 'arm64-v8a
         let android_abi_name = match *arch {
             "arm" => "armeabi-v7a",
@@ -139,19 +175,8 @@ pub fn create_apk(package_name: &str, output_dir: &str, install: bool) {
         ));
 
         let output_dir = output_dir.to_string();
-        let package_name = package_name.to_string();
+        let package_url = package.package_url();
         join_handles.push(thread::spawn(move || {
-            let http_client = reqwest::blocking::Client::new();
-            let packages = fetch_repo(arch);
-            let bootstrap_package = packages
-                .get(&package_name)
-                .unwrap_or_else(|| panic!("Cannot find package '{}'", package_name));
-            let package_url = bootstrap_package.package_url();
-
-            let mut response = http_client
-                .get(&package_url)
-                .send()
-                .unwrap_or_else(|_| panic!("Failed fetching {}", package_url));
             let mut visitor = CreateApkVisitor {
                 output_directory: format!(
                     "{}/app/src/main/jniLibs/{}",
@@ -161,7 +186,10 @@ pub fn create_apk(package_name: &str, output_dir: &str, install: bool) {
                 file_mapping: String::new(),
                 symlinks: String::new(),
             };
-            visit_files(&mut response, &mut visitor);
+            File::open(&deb_path)
+                .map_err(DebError::from)
+                .and_then(|file| visit_files(&mut BufReader::new(file), &mut visitor))
+                .unwrap_or_else(|e| panic!("Error reading {}: {}", package_url, e));
 
             write_string_to_file(
                 &format!(
@@ -186,15 +214,17 @@ pub fn create_apk(package_name: &str, output_dir: &str, install: bool) {
     let path_to_gradlew = std::fs::canonicalize(format!("{}/gradlew", output_dir)).unwrap();
     if install {
         println!("Executing {:?}", path_to_gradlew);
+        // Return right away and leave gradle installing in the background.
+        #[allow(clippy::zombie_processes)]
         std::process::Command::new(path_to_gradlew)
-            .args(&["installDebug"])
+            .args(["installDebug"])
             .current_dir(output_dir)
             .spawn()
             .expect("failed to execute process");
     } else {
         println!("Executing {:?}", path_to_gradlew);
         std::process::Command::new(path_to_gradlew)
-            .args(&["assembleDebug"])
+            .args(["assembleDebug"])
             .current_dir(output_dir)
             .spawn()
             .expect("failed to execute process")
@@ -0,0 +1,434 @@
+//! Parsing and serialization of deb822 paragraphs, the format used by
+//! control files, Packages indexes and Release files.
+//!
+//! Paragraphs keep field order, multi-line values, comments, `\r\n` line
+//! endings and a missing final newline exactly as read, so that serializing
+//! a parsed paragraph reproduces its input.
+use std::fmt;
+use std::io::{self, BufRead};
+use std::ops::Index;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    Syntax { line: usize, message: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "{}", e),
+            ParseError::Syntax { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<io::Error> for ParseError {
+    fn from(error: io::Error) -> Self {
+        ParseError::Io(error)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Line {
+    /// A field name and the raw text following its colon, including any
+    /// continuation lines but without the final newline.
+    Field { name: String, raw_value: String },
+    /// A comment line, including the leading `#`.
+    Comment(String),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Paragraph {
+    lines: Vec<Line>,
+    /// If lines end with `\r\n` instead of `\n`.
+    crlf: bool,
+    /// If the last line has no line ending, as at the end of a file without
+    /// a final newline.
+    missing_final_newline: bool,
+}
+
+impl Paragraph {
+    pub fn new() -> Paragraph {
+        Paragraph::default()
+    }
+
+    fn raw_value(&self, name: &str) -> Option<&str> {
+        self.lines.iter().find_map(|line| match line {
+            Line::Field {
+                name: field_name,
+                raw_value,
+            } if field_name.eq_ignore_ascii_case(name) => Some(raw_value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The value of a field, looked up case-insensitively. Multi-line values
+    /// keep their continuation lines, including the leading space.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.raw_value(name)
+            .map(|raw| raw.trim_start_matches([' ', '\t']).trim_end())
+    }
+
+    /// The continuation lines of a multi-line field such as `Conffiles`,
+    /// with surrounding whitespace removed.
+    pub fn get_lines<'a>(&'a self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.get_lines_with_offsets(name).map(|(_, line)| line)
+    }
+
+    /// The continuation lines of a multi-line field like `get_lines`, each
+    /// with the number of lines before it in the paragraph, for reporting
+    /// errors in them.
+    pub fn get_lines_with_offsets<'a>(
+        &'a self,
+        name: &str,
+    ) -> impl Iterator<Item = (usize, &'a str)> {
+        let mut field_offset = 0;
+        for line in &self.lines {
+            match line {
+                Line::Field {
+                    name: field_name, ..
+                } if field_name.eq_ignore_ascii_case(name) => break,
+                Line::Field { raw_value, .. } => {
+                    field_offset += 1 + raw_value.matches('\n').count()
+                }
+                Line::Comment(_) => field_offset += 1,
+            }
+        }
+        self.get(name)
+            .unwrap_or("")
+            .lines()
+            .enumerate()
+            .skip(1)
+            .map(move |(index, line)| (field_offset + index, line.trim()))
+            .filter(|(_, line)| !line.is_empty())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.raw_value(name).is_some()
+    }
+
+    /// Iterate over field names and values in their original order.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Field { name, raw_value } => Some((
+                name.as_str(),
+                raw_value.trim_start_matches([' ', '\t']).trim_end(),
+            )),
+            Line::Comment(_) => None,
+        })
+    }
+
+    /// Set the value of a field, keeping its position if already present and
+    /// appending it otherwise. Multi-line values should have continuation
+    /// lines starting with a space.
+    pub fn set(&mut self, name: &str, value: &str) {
+        let new_raw_value = if value.is_empty() || value.starts_with('\n') {
+            value.to_string()
+        } else {
+            format!(" {}", value)
+        };
+        for line in self.lines.iter_mut() {
+            if let Line::Field {
+                name: field_name,
+                raw_value,
+            } = line
+            {
+                if field_name.eq_ignore_ascii_case(name) {
+                    *raw_value = new_raw_value;
+                    return;
+                }
+            }
+        }
+        self.lines.push(Line::Field {
+            name: name.to_string(),
+            raw_value: new_raw_value,
+        });
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let value = self.get(name)?.to_string();
+        self.lines.retain(|line| match line {
+            Line::Field {
+                name: field_name, ..
+            } => !field_name.eq_ignore_ascii_case(name),
+            Line::Comment(_) => true,
+        });
+        Some(value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+impl Index<&str> for Paragraph {
+    type Output = str;
+
+    fn index(&self, name: &str) -> &str {
+        self.get(name)
+            .unwrap_or_else(|| panic!("No field '{}'", name))
+    }
+}
+
+impl fmt::Display for Paragraph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line_ending = if self.crlf { "\r\n" } else { "\n" };
+        for (i, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::Field { name, raw_value } => {
+                    write!(f, "{}:{}", name, raw_value.replace('\n', line_ending))?
+                }
+                Line::Comment(comment) => write!(f, "{}", comment)?,
+            }
+            if i + 1 < self.lines.len() || !self.missing_final_newline {
+                f.write_str(line_ending)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Paragraph {
+    type Err = ParseError;
+
+    /// Parse a text containing exactly one paragraph, such as a control file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut paragraphs = Paragraphs::new(s.as_bytes());
+        let paragraph = paragraphs.next().unwrap_or_else(|| Ok(Paragraph::new()))?;
+        if paragraphs.next().is_some() {
+            return Err(ParseError::Syntax {
+                line: paragraphs.line_number,
+                message: "expected a single paragraph".to_string(),
+            });
+        }
+        Ok(paragraph)
+    }
+}
+
+/// Iterator over the paragraphs of a deb822 file such as a Packages index.
+pub struct Paragraphs<R: BufRead> {
+    reader: R,
+    line_number: usize,
+    paragraph_line: usize,
+    /// The line ending of the last line read, empty at the end of a file
+    /// without a final newline.
+    line_ending: &'static str,
+}
+
+impl<R: BufRead> Paragraphs<R> {
+    pub fn new(reader: R) -> Paragraphs<R> {
+        Paragraphs {
+            reader,
+            line_number: 0,
+            paragraph_line: 0,
+            line_ending: "\n",
+        }
+    }
+
+    /// The line number of the first field of the last paragraph read, for
+    /// reporting errors found in its contents.
+    pub fn paragraph_line(&self) -> usize {
+        self.paragraph_line
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, ParseError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        self.line_number += 1;
+        self.line_ending = if line.ends_with("\r\n") {
+            "\r\n"
+        } else if line.ends_with('\n') {
+            "\n"
+        } else {
+            ""
+        };
+        line.truncate(line.len() - self.line_ending.len());
+        Ok(Some(line))
+    }
+
+    fn syntax_error(&self, message: String) -> ParseError {
+        ParseError::Syntax {
+            line: self.line_number,
+            message,
+        }
+    }
+
+    fn read_paragraph(&mut self) -> Result<Option<Paragraph>, ParseError> {
+        let mut paragraph = Paragraph::new();
+        self.paragraph_line = 0;
+        while let Some(line) = self.read_line()? {
+            let is_first_line = paragraph.is_empty();
+            if line.trim().is_empty() {
+                if paragraph.is_empty() {
+                    continue;
+                }
+                break;
+            } else if line.starts_with('#') {
+                paragraph.lines.push(Line::Comment(line));
+            } else if line.starts_with(' ') || line.starts_with('\t') {
+                match paragraph.lines.last_mut() {
+                    Some(Line::Field { raw_value, .. }) => {
+                        raw_value.push('\n');
+                        raw_value.push_str(&line);
+                    }
+                    _ => {
+                        return Err(
+                            self.syntax_error("continuation line without a field".to_string())
+                        )
+                    }
+                }
+            } else {
+                let (name, raw_value) = line
+                    .split_once(':')
+                    .ok_or_else(|| self.syntax_error(format!("invalid line '{}'", line)))?;
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    return Err(self.syntax_error(format!("invalid field name '{}'", name)));
+                }
+                if self.paragraph_line == 0 {
+                    self.paragraph_line = self.line_number;
+                }
+                paragraph.lines.push(Line::Field {
+                    name: name.to_string(),
+                    raw_value: raw_value.to_string(),
+                });
+            }
+            if is_first_line {
+                paragraph.crlf = self.line_ending == "\r\n";
+            }
+            paragraph.missing_final_newline = self.line_ending.is_empty();
+        }
+        Ok(if paragraph.is_empty() {
+            None
+        } else {
+            Some(paragraph)
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for Paragraphs<R> {
+    type Item = Result<Paragraph, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_paragraph().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTROL: &str = "Package: abduco
+Version: 0.6
+# A comment
+Depends: libutil,dvtm
+Conffiles:
+ /data/data/com.termux/files/usr/etc/abduco.conf 0123456789abcdef
+Description: Clean and simple terminal session manager
+ abduco provides session management.
+ .
+ It allows programs to be run independently from their controlling terminal.
+";
+
+    #[test]
+    fn test_parse_paragraph() {
+        let paragraph: Paragraph = CONTROL.parse().unwrap();
+        assert_eq!("abduco", &paragraph["Package"]);
+        assert_eq!(Some("0.6"), paragraph.get("version"));
+        assert_eq!(
+            Some(
+                "Clean and simple terminal session manager
+ abduco provides session management.
+ .
+ It allows programs to be run independently from their controlling terminal."
+            ),
+            paragraph.get("Description")
+        );
+        assert_eq!(
+            vec!["/data/data/com.termux/files/usr/etc/abduco.conf 0123456789abcdef"],
+            paragraph.get_lines("Conffiles").collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["Package", "Version", "Depends", "Conffiles", "Description"],
+            paragraph.fields().map(|(name, _)| name).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let paragraph: Paragraph = CONTROL.parse().unwrap();
+        assert_eq!(CONTROL, paragraph.to_string());
+    }
+
+    #[test]
+    fn test_round_trip_line_endings() {
+        let crlf = CONTROL.replace('\n', "\r\n");
+        let paragraph: Paragraph = crlf.parse().unwrap();
+        assert_eq!(Some("0.6"), paragraph.get("Version"));
+        assert_eq!(
+            Some("Clean and simple terminal session manager\n abduco provides session management.\n .\n It allows programs to be run independently from their controlling terminal."),
+            paragraph.get("Description")
+        );
+        assert_eq!(crlf, paragraph.to_string());
+
+        let unterminated = CONTROL.trim_end();
+        let paragraph: Paragraph = unterminated.parse().unwrap();
+        assert_eq!(unterminated, paragraph.to_string());
+        let paragraph: Paragraph = crlf.trim_end().parse().unwrap();
+        assert_eq!(crlf.trim_end(), paragraph.to_string());
+
+        let input = "Package: a\r\n\r\nPackage: b\nVersion: 2";
+        let paragraphs = Paragraphs::new(input.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!("Package: a\r\n", paragraphs[0].to_string());
+        assert_eq!("Package: b\nVersion: 2", paragraphs[1].to_string());
+        let mut paragraph = Paragraph::new();
+        paragraph.set("Package", "a");
+        assert_eq!("Package: a\n", paragraph.to_string());
+    }
+
+    #[test]
+    fn test_set_and_remove() {
+        let mut paragraph: Paragraph = CONTROL.parse().unwrap();
+        paragraph.set("version", "0.7");
+        paragraph.set("Installed-Size", "68");
+        assert_eq!(
+            Some("libutil,dvtm".to_string()),
+            paragraph.remove("Depends")
+        );
+        assert!(paragraph
+            .to_string()
+            .starts_with("Package: abduco\nVersion: 0.7\n# A comment\nConffiles:\n"));
+        assert!(paragraph.to_string().ends_with("Installed-Size: 68\n"));
+    }
+
+    #[test]
+    fn test_parse_paragraphs() {
+        let input = "\nPackage: a\nVersion: 1\n\n\nPackage: b\nVersion: 2";
+        let paragraphs = Paragraphs::new(input.as_bytes())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(2, paragraphs.len());
+        assert_eq!("b", &paragraphs[1]["Package"]);
+        assert_eq!("2", &paragraphs[1]["Version"]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            "Package: a\nno colon here\n".parse::<Paragraph>(),
+            Err(ParseError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            " continuation\n".parse::<Paragraph>(),
+            Err(ParseError::Syntax { line: 1, .. })
+        ));
+        assert!("Package: a\n\nPackage: b\n".parse::<Paragraph>().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+//! Creation of deb files from a staging directory, like `dpkg-deb --build`.
+use crate::deb822::Paragraph;
+use crate::deb_file::{Compression, DebError};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use walkdir::WalkDir;
+
+/// Control files which should be executable.
+const MAINTAINER_SCRIPTS: [&str; 5] = ["preinst", "postinst", "prerm", "postrm", "config"];
+
+/// Fields which must be present in a control file to build a package.
+const REQUIRED_FIELDS: [&str; 3] = ["Package", "Version", "Architecture"];
+
+fn new_header(entry_type: tar::EntryType, mode: u32, mtime: u64) -> io::Result<tar::Header> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_username("root")?;
+    header.set_groupname("root")?;
+    header.set_size(0);
+    Ok(header)
+}
+
+/// Write a name into a header field, preceded by a GNU long name entry if it does
+/// not fit. This is done by hand since the tar crate strips the leading `./`,
+/// which dpkg and the rest of this crate expect.
+fn set_name(
+    tar: &mut tar::Builder<impl Write>,
+    long_name_type: tar::EntryType,
+    field: &mut [u8],
+    name: &str,
+) -> io::Result<()> {
+    let bytes = name.as_bytes();
+    if bytes.len() > field.len() {
+        let mut long_name_header = new_header(long_name_type, 0o644, 0)?;
+        long_name_header.as_gnu_mut().unwrap().name[..13].copy_from_slice(b"././@LongLink");
+        long_name_header.set_size(bytes.len() as u64 + 1);
+        long_name_header.set_cksum();
+        tar.append(&long_name_header, bytes.chain(&[0u8][..]))?;
+    }
+    let length = bytes.len().min(field.len());
+    field[..length].copy_from_slice(&bytes[..length]);
+    Ok(())
+}
+
+fn append_entry(
+    tar: &mut tar::Builder<impl Write>,
+    mut header: tar::Header,
+    path: &str,
+    link_name: Option<&str>,
+    data: impl Read,
+) -> io::Result<()> {
+    if let Some(link_name) = link_name {
+        let field = &mut header.as_old_mut().linkname;
+        set_name(tar, tar::EntryType::GNULongLink, field, link_name)?;
+    }
+    set_name(
+        tar,
+        tar::EntryType::GNULongName,
+        &mut header.as_old_mut().name,
+        path,
+    )?;
+    header.set_cksum();
+    tar.append(&header, data)
+}
+
+/// An uncompressed data.tar with information about its contents.
+struct DataTar {
+    tar: Vec<u8>,
+    md5sums: String,
+    installed_size_kib: u64,
+}
+
+fn build_data_tar(staging_dir: &Path) -> Result<DataTar, DebError> {
+    let mut tar = tar::Builder::new(Vec::new());
+    let mut md5sums = String::new();
+    let mut installed_size_kib = 0;
+    // Maps device and inode of files with several links to their first path and md5 digest.
+    let mut hardlinks: HashMap<(u64, u64), (String, String)> = HashMap::new();
+
+    let root_metadata = fs::metadata(staging_dir)?;
+    let root_header = new_header(
+        tar::EntryType::Directory,
+        root_metadata.mode() & 0o7777,
+        root_metadata.mtime() as u64,
+    )?;
+    append_entry(&mut tar, root_header, "./", None, io::empty())?;
+
+    let walker = WalkDir::new(staging_dir)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_entry(|entry| !(entry.depth() == 1 && entry.file_name() == "DEBIAN"));
+    for entry in walker {
+        let entry = entry.map_err(io::Error::from)?;
+        let relative_path = entry
+            .path()
+            .strip_prefix(staging_dir)
+            .expect("Walked path outside staging directory")
+            .to_str()
+            .ok_or_else(|| io::Error::other(format!("Non UTF-8 path {:?}", entry.path())))?;
+        let metadata = entry.path().symlink_metadata()?;
+        let mode = metadata.mode() & 0o7777;
+        let mtime = metadata.mtime() as u64;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            let header = new_header(tar::EntryType::Directory, mode, mtime)?;
+            let path = format!("./{}/", relative_path);
+            append_entry(&mut tar, header, &path, None, io::empty())?;
+            installed_size_kib += 1;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(entry.path())?;
+            let target = target
+                .to_str()
+                .ok_or_else(|| io::Error::other(format!("Non UTF-8 link {:?}", target)))?;
+            let header = new_header(tar::EntryType::Symlink, 0o777, mtime)?;
+            let path = format!("./{}", relative_path);
+            append_entry(&mut tar, header, &path, Some(target), io::empty())?;
+            installed_size_kib += 1;
+        } else if file_type.is_file() {
+            let path = format!("./{}", relative_path);
+            let key = (metadata.dev(), metadata.ino());
+            if let Some((first_path, digest)) = hardlinks.get(&key) {
+                md5sums.push_str(&format!("{}  {}\n", digest, relative_path));
+                let header = new_header(tar::EntryType::Link, mode, mtime)?;
+                append_entry(&mut tar, header, &path, Some(first_path), io::empty())?;
+                continue;
+            }
+
+            let contents = fs::read(entry.path())?;
+            let digest = format!("{:x}", md5::compute(&contents));
+            md5sums.push_str(&format!("{}  {}\n", digest, relative_path));
+            installed_size_kib += (contents.len() as u64).div_ceil(1024);
+            if metadata.nlink() > 1 {
+                hardlinks.insert(key, (path.clone(), digest));
+            }
+
+            let mut header = new_header(tar::EntryType::Regular, mode, mtime)?;
+            header.set_size(contents.len() as u64);
+            append_entry(&mut tar, header, &path, None, &contents[..])?;
+        } else {
+            return Err(DebError::Io(io::Error::other(format!(
+                "Unsupported file type: {:?}",
+                entry.path()
+            ))));
+        }
+    }
+
+    Ok(DataTar {
+        tar: tar.into_inner()?,
+        md5sums,
+        installed_size_kib,
+    })
+}
+
+fn build_control_tar(
+    control_dir: &Path,
+    control: &Paragraph,
+    md5sums: &str,
+) -> Result<Vec<u8>, DebError> {
+    let mtime = fs::metadata(control_dir.join("control"))?.mtime() as u64;
+    let mut tar = tar::Builder::new(Vec::new());
+
+    let root_header = new_header(tar::EntryType::Directory, 0o755, mtime)?;
+    append_entry(&mut tar, root_header, "./", None, io::empty())?;
+
+    let mut append_file = |name: &str, mode: u32, mtime: u64, contents: &[u8]| {
+        let mut header = new_header(tar::EntryType::Regular, mode, mtime)?;
+        header.set_size(contents.len() as u64);
+        append_entry(&mut tar, header, &format!("./{}", name), None, contents)
+    };
+
+    append_file("control", 0o644, mtime, control.to_string().as_bytes())?;
+    if !md5sums.is_empty() {
+        append_file("md5sums", 0o644, mtime, md5sums.as_bytes())?;
+    }
+
+    let mut entries = fs::read_dir(control_dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let file_name = entry.file_name();
+        let name = file_name
+            .to_str()
+            .ok_or_else(|| io::Error::other(format!("Non UTF-8 path {:?}", entry.path())))?;
+        if name == "control" || name == "md5sums" {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            return Err(DebError::Io(io::Error::other(format!(
+                "Not a regular file: {:?}",
+                entry.path()
+            ))));
+        }
+        let mode = if MAINTAINER_SCRIPTS.contains(&name) {
+            0o755
+        } else {
+            0o644
+        };
+        append_file(
+            name,
+            mode,
+            metadata.mtime() as u64,
+            &fs::read(entry.path())?,
+        )?;
+    }
+
+    Ok(tar.into_inner()?)
+}
+
+/// Write a deb file from uncompressed control.tar and data.tar archives.
+pub fn write_deb(
+    output: &mut impl Write,
+    compression: Compression,
+    control_tar: &[u8],
+    data_tar: &[u8],
+) -> io::Result<()> {
+    let control_compression = compression.for_control_tar();
+    let members = [
+        ("debian-binary".to_string(), b"2.0\n".to_vec()),
+        (
+            format!("control.tar{}", control_compression.extension()),
+            control_compression.compress(control_tar)?,
+        ),
+        (
+            format!("data.tar{}", compression.extension()),
+            compression.compress(data_tar)?,
+        ),
+    ];
+
+    let mut archive = ar::Builder::new(output);
+    for (name, contents) in &members {
+        let mut header = ar::Header::new(name.as_bytes().to_vec(), contents.len() as u64);
+        header.set_mode(0o100644);
+        archive.append(&header, &contents[..])?;
+    }
+    Ok(())
+}
+
+/// Build a deb file from a staging directory containing a `DEBIAN` directory
+/// with the control file and maintainer scripts, and the data tree to package.
+///
+/// The `Installed-Size` field and the `md5sums` file are computed from the data
+/// tree, and all files are owned by root. Returns the written control file.
+pub fn build_deb(
+    staging_dir: &Path,
+    compression: Compression,
+    output: &mut impl Write,
+) -> Result<Paragraph, DebError> {
+    let control_dir = staging_dir.join("DEBIAN");
+    let mut control: Paragraph = fs::read_to_string(control_dir.join("control"))?
+        .parse()
+        .map_err(|e| DebError::MalformedControl(format!("{}", e)))?;
+    for field in &REQUIRED_FIELDS {
+        if !control.contains(field) {
+            return Err(DebError::MalformedControl(format!(
+                "missing {} field",
+                field
+            )));
+        }
+    }
+
+    let data_tar = build_data_tar(staging_dir)?;
+    control.set("Installed-Size", &data_tar.installed_size_kib.to_string());
+    let control_tar = build_control_tar(&control_dir, &control, &data_tar.md5sums)?;
+
+    write_deb(output, compression, &control_tar, &data_tar.tar)?;
+    Ok(control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deb_file::{visit_files, DebVisitor};
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[derive(Default)]
+    struct CollectingVisitor {
+        control: Option<Paragraph>,
+        files: Vec<(String, tar::EntryType, String)>,
+    }
+
+    impl DebVisitor for CollectingVisitor {
+        fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError> {
+            self.control = Some(control);
+            Ok(())
+        }
+
+        fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+            let path = file.path()?.to_str().unwrap().to_string();
+            let entry_type = file.header().entry_type();
+            let mut contents = String::new();
+            if let Some(link_name) = file.link_name()? {
+                contents = link_name.to_str().unwrap().to_string();
+            } else {
+                file.read_to_string(&mut contents)?;
+            }
+            self.files.push((path, entry_type, contents));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_build_deb() {
+        let staging_dir = tempfile::tempdir().unwrap();
+        let staging_path = staging_dir.path();
+        let bin_dir = staging_path.join("data/data/com.termux/files/usr/bin");
+        fs::create_dir_all(staging_path.join("DEBIAN")).unwrap();
+        fs::create_dir_all(&bin_dir).unwrap();
+        fs::write(
+            staging_path.join("DEBIAN/control"),
+            "Package: hello\nVersion: 1.0\nArchitecture: all\nDescription: Hello\n",
+        )
+        .unwrap();
+        fs::write(staging_path.join("DEBIAN/postinst"), "#!/bin/sh\n").unwrap();
+        fs::write(bin_dir.join("hello"), "echo hello\n").unwrap();
+        fs::set_permissions(bin_dir.join("hello"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::hard_link(bin_dir.join("hello"), bin_dir.join("hi")).unwrap();
+        symlink("hello", bin_dir.join("hey")).unwrap();
+        let long_name = "x".repeat(120);
+        fs::write(bin_dir.join(&long_name), "").unwrap();
+
+        for compression in &[
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Bzip2,
+            Compression::Zstd,
+        ] {
+            let mut deb = Vec::new();
+            let control = build_deb(staging_path, *compression, &mut deb).unwrap();
+            assert_eq!("8", &control["Installed-Size"]);
+
+            let mut visitor = CollectingVisitor::default();
+            visit_files(&mut &deb[..], &mut visitor).unwrap();
+            assert_eq!(Some(control), visitor.control);
+
+            let prefix = "./data/data/com.termux/files/usr/bin/";
+            let files: Vec<_> = visitor
+                .files
+                .iter()
+                .map(|(path, entry_type, contents)| (path.as_str(), *entry_type, contents.as_str()))
+                .collect();
+            assert_eq!("./", files[0].0);
+            assert_eq!(
+                &[
+                    (prefix, tar::EntryType::Directory, ""),
+                    (
+                        &format!("{}hello", prefix)[..],
+                        tar::EntryType::Regular,
+                        "echo hello\n"
+                    ),
+                    (
+                        &format!("{}hey", prefix)[..],
+                        tar::EntryType::Symlink,
+                        "hello"
+                    ),
+                    (
+                        &format!("{}hi", prefix)[..],
+                        tar::EntryType::Link,
+                        &format!("{}hello", prefix)[..]
+                    ),
+                    (
+                        &format!("{}{}", prefix, long_name)[..],
+                        tar::EntryType::Regular,
+                        ""
+                    ),
+                ],
+                &files[6..]
+            );
+        }
+    }
+
+    #[test]
+    fn test_build_deb_missing_field() {
+        let staging_dir = tempfile::tempdir().unwrap();
+        fs::create_dir(staging_dir.path().join("DEBIAN")).unwrap();
+        fs::write(staging_dir.path().join("DEBIAN/control"), "Package: a\n").unwrap();
+        let result = build_deb(staging_dir.path(), Compression::Xz, &mut Vec::new());
+        assert!(matches!(result, Err(DebError::MalformedControl(_))));
+    }
+}
@@ -1,96 +1,774 @@
+use crate::deb822::Paragraph;
 use std::collections::HashMap;
-use std::io::{BufRead, Read};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
 
+/// Errors encountered while reading a deb file.
+#[derive(Debug)]
+pub enum DebError {
+    /// The ar container is truncated or has an invalid header.
+    BadArHeader(io::Error),
+    /// The ar container has a member which is not part of the deb format.
+    UnknownMember(String),
+    /// A control.tar or data.tar member could not be decompressed.
+    Decompression(io::Error),
+    /// The control file could not be parsed.
+    MalformedControl(String),
+    /// A control.tar or data.tar member is not a valid tar archive.
+    Tar(io::Error),
+    /// A path in the package is absolute or escapes its root with `..`.
+    UnsafePath(String),
+    /// Any other I/O error, such as one raised by a visitor.
+    Io(io::Error),
+}
+
+impl fmt::Display for DebError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebError::BadArHeader(e) => write!(f, "bad ar archive: {}", e),
+            DebError::UnknownMember(name) => write!(f, "unknown deb member '{}'", name),
+            DebError::Decompression(e) => write!(f, "decompression failed: {}", e),
+            DebError::MalformedControl(message) => write!(f, "malformed control: {}", message),
+            DebError::Tar(e) => write!(f, "bad tar archive: {}", e),
+            DebError::UnsafePath(path) => write!(f, "unsafe path '{}'", path),
+            DebError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DebError {}
+
+impl From<io::Error> for DebError {
+    fn from(error: io::Error) -> Self {
+        if DecompressionFailed::is_cause_of(&error) {
+            DebError::Decompression(error)
+        } else {
+            DebError::Io(error)
+        }
+    }
+}
+
+impl DebError {
+    fn from_tar(error: io::Error) -> Self {
+        if DecompressionFailed::is_cause_of(&error) {
+            DebError::Decompression(error)
+        } else {
+            DebError::Tar(error)
+        }
+    }
+}
+
+/// Marker wrapped around errors from decompressors, to tell them apart from tar errors.
+#[derive(Debug)]
+struct DecompressionFailed(io::Error);
+
+impl DecompressionFailed {
+    fn wrap(error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), DecompressionFailed(error))
+    }
+
+    fn is_cause_of(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|inner| inner.is::<DecompressionFailed>())
+    }
+}
+
+impl fmt::Display for DecompressionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for DecompressionFailed {}
+
+struct DecompressingReader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Read for DecompressingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf).map_err(DecompressionFailed::wrap)
+    }
+}
+
+/// Maintainer scripts run by dpkg when installing and removing a package.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MaintainerScript {
+    Preinst,
+    Postinst,
+    Prerm,
+    Postrm,
+}
+
+impl MaintainerScript {
+    pub const ALL: [MaintainerScript; 4] = [
+        MaintainerScript::Preinst,
+        MaintainerScript::Postinst,
+        MaintainerScript::Prerm,
+        MaintainerScript::Postrm,
+    ];
+
+    /// The name of the script in control.tar and in the dpkg info directory.
+    pub fn name(self) -> &'static str {
+        match self {
+            MaintainerScript::Preinst => "preinst",
+            MaintainerScript::Postinst => "postinst",
+            MaintainerScript::Prerm => "prerm",
+            MaintainerScript::Postrm => "postrm",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<MaintainerScript> {
+        MaintainerScript::ALL
+            .iter()
+            .copied()
+            .find(|script| script.name() == name)
+    }
+}
+
+/// Visitor over the contents of a deb file. All members of control.tar are
+/// visited before `visit_control` is called with the parsed control file.
 pub trait DebVisitor {
-    fn visit_control(&mut self, fields: HashMap<String, String>);
-    fn visit_conffiles(&mut self, _file: &mut tar::Entry<impl Read>) {
+    fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError>;
+    fn visit_conffiles(&mut self, _file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        // Default implementation does nothing.
+        Ok(())
+    }
+    fn visit_maintainer_script(
+        &mut self,
+        _script: MaintainerScript,
+        _file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        // Default implementation does nothing.
+        Ok(())
+    }
+    fn visit_md5sums(&mut self, _file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        // Default implementation does nothing.
+        Ok(())
+    }
+    fn visit_triggers(&mut self, _file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        // Default implementation does nothing.
+        Ok(())
+    }
+    fn visit_shlibs(&mut self, _file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
         // Default implementation does nothing.
+        Ok(())
+    }
+    /// Visit control.tar members without a dedicated hook, such as `config` or `symbols`.
+    fn visit_other_control_file(
+        &mut self,
+        _name: &str,
+        _file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        // Default implementation does nothing.
+        Ok(())
+    }
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError>;
+}
+
+/// Compression applied to the control.tar and data.tar members of a deb file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Lzma,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Xz => ".xz",
+            Compression::Lzma => ".lzma",
+            Compression::Bzip2 => ".bz2",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    fn from_extension(extension: &str) -> Option<Compression> {
+        match extension {
+            "" => Some(Compression::None),
+            ".gz" => Some(Compression::Gzip),
+            ".xz" => Some(Compression::Xz),
+            ".lzma" => Some(Compression::Lzma),
+            ".bz2" => Some(Compression::Bzip2),
+            ".zst" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn decompress<'a>(self, reader: impl Read + 'a) -> Result<Box<dyn Read + 'a>, DebError> {
+        let decompressor: Box<dyn Read + 'a> = match self {
+            Compression::None => return Ok(Box::new(reader)),
+            Compression::Gzip => {
+                Box::new(libflate::gzip::Decoder::new(reader).map_err(DebError::Decompression)?)
+            }
+            // The liblzma auto decoder handles both the xz and legacy lzma formats.
+            Compression::Xz | Compression::Lzma => Box::new(
+                lzma::reader::LzmaReader::new_decompressor(reader)
+                    .map_err(|e| DebError::Decompression(io::Error::other(e)))?,
+            ),
+            Compression::Bzip2 => Box::new(bzip2::read::BzDecoder::new(reader)),
+            Compression::Zstd => {
+                Box::new(zstd::stream::read::Decoder::new(reader).map_err(DebError::Decompression)?)
+            }
+        };
+        Ok(Box::new(DecompressingReader {
+            inner: decompressor,
+        }))
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = libflate::gzip::Encoder::new(Vec::new())?;
+                encoder.write_all(data)?;
+                encoder.finish().into_result()
+            }
+            Compression::Xz => lzma::compress(data, 6).map_err(io::Error::other),
+            Compression::Lzma => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "lzma compression is not supported, use xz instead",
+            )),
+            Compression::Bzip2 => {
+                let mut compressed = Vec::new();
+                bzip2::read::BzEncoder::new(data, bzip2::Compression::default())
+                    .read_to_end(&mut compressed)?;
+                Ok(compressed)
+            }
+            Compression::Zstd => zstd::stream::encode_all(data, 0),
+        }
+    }
+
+    /// The compression to use for control.tar when data.tar uses this one,
+    /// since dpkg does not accept bzip2 or lzma for control.tar.
+    pub fn for_control_tar(self) -> Compression {
+        match self {
+            Compression::Lzma | Compression::Bzip2 => Compression::Xz,
+            compression => compression,
+        }
+    }
+}
+
+/// Parses the compressions which debs can be built with, so lzma, which can
+/// only be read, is rejected.
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "xz" => Ok(Compression::Xz),
+            "lzma" => Err("lzma compression is not supported, use xz instead".to_string()),
+            "bzip2" => Ok(Compression::Bzip2),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression '{}'", s)),
+        }
+    }
+}
+
+/// The members of the ar archive making up a deb file.
+pub(crate) enum ArMember {
+    DebianBinary,
+    ControlTar(Compression),
+    DataTar(Compression),
+    Ignored,
+}
+
+impl ArMember {
+    /// Classify an ar member by name, accepting the same compressions as dpkg.
+    pub(crate) fn from_name(name: &str) -> Option<ArMember> {
+        // GNU ar terminates member names with a slash.
+        let name = name.strip_suffix('/').unwrap_or(name);
+        if name == "debian-binary" {
+            Some(ArMember::DebianBinary)
+        } else if let Some(extension) = name.strip_prefix("control.tar") {
+            match Compression::from_extension(extension)? {
+                Compression::Lzma | Compression::Bzip2 => None,
+                compression => Some(ArMember::ControlTar(compression)),
+            }
+        } else if let Some(extension) = name.strip_prefix("data.tar") {
+            Compression::from_extension(extension).map(ArMember::DataTar)
+        } else if name.starts_with('_') {
+            // Members starting with an underscore are reserved and skipped by dpkg.
+            Some(ArMember::Ignored)
+        } else {
+            None
+        }
     }
-    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>);
 }
 
 enum ControlTarEntryType {
     Control,
     Conffiles,
-    Other,
+    MaintainerScript(MaintainerScript),
+    Md5sums,
+    Triggers,
+    Shlibs,
+    Other(String),
+    Directory,
 }
 
 fn parse_control_ar_entry(
-    xz_encoded: bool,
+    compression: Compression,
     ar_entry: ar::Entry<impl std::io::Read>,
     visitor: &mut impl DebVisitor,
-) -> HashMap<String, String> {
-    let mut map = HashMap::new();
-
-    let reader: Box<dyn Read> = if xz_encoded {
-        Box::new(lzma::reader::LzmaReader::new_decompressor(ar_entry).expect("Error decompressing"))
-    } else {
-        Box::new(libflate::gzip::Decoder::new(ar_entry).expect("Error decompressing"))
-    };
-    let mut control_tar = tar::Archive::new(reader);
-    for file in control_tar.entries().unwrap() {
-        let mut file = file.unwrap();
-
-        let entry_type = {
-            let path = file.path().expect("Error reading path");
-            let path_str = path.to_str().expect("Could not read path");
-            match path_str {
-                "./control" => ControlTarEntryType::Control,
-                "./conffiles" => ControlTarEntryType::Conffiles,
-                _ => ControlTarEntryType::Other,
+) -> Result<Paragraph, DebError> {
+    let mut control = None;
+
+    let mut control_tar = tar::Archive::new(compression.decompress(ar_entry)?);
+    for file in control_tar.entries().map_err(DebError::from_tar)? {
+        let mut file = file.map_err(DebError::from_tar)?;
+
+        let entry_type = if file.header().entry_type().is_dir() {
+            ControlTarEntryType::Directory
+        } else {
+            let path = file.path().map_err(DebError::from_tar)?;
+            let path = path.to_string_lossy();
+            match path.strip_prefix("./").unwrap_or(&path) {
+                "control" => ControlTarEntryType::Control,
+                "conffiles" => ControlTarEntryType::Conffiles,
+                "md5sums" => ControlTarEntryType::Md5sums,
+                "triggers" => ControlTarEntryType::Triggers,
+                "shlibs" => ControlTarEntryType::Shlibs,
+                name => match MaintainerScript::from_name(name) {
+                    Some(script) => ControlTarEntryType::MaintainerScript(script),
+                    None => ControlTarEntryType::Other(name.to_string()),
+                },
             }
         };
         match entry_type {
             ControlTarEntryType::Control => {
-                for line in std::io::BufReader::new(file).lines() {
-                    let line = line.unwrap();
-                    if !line.starts_with(' ') {
-                        let mut splitter = line[..].splitn(2, ": ");
-                        let key = splitter.next().unwrap();
-                        let value = splitter.next().unwrap();
-                        map.insert(String::from(key), String::from(value));
-                    };
-                }
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)
+                    .map_err(DebError::from_tar)?;
+                control = Some(
+                    contents
+                        .parse()
+                        .map_err(|e| DebError::MalformedControl(format!("{}", e)))?,
+                );
             }
             ControlTarEntryType::Conffiles => {
-                visitor.visit_conffiles(&mut file);
+                visitor.visit_conffiles(&mut file)?;
             }
-            _ => {}
+            ControlTarEntryType::MaintainerScript(script) => {
+                visitor.visit_maintainer_script(script, &mut file)?;
+            }
+            ControlTarEntryType::Md5sums => {
+                visitor.visit_md5sums(&mut file)?;
+            }
+            ControlTarEntryType::Triggers => {
+                visitor.visit_triggers(&mut file)?;
+            }
+            ControlTarEntryType::Shlibs => {
+                visitor.visit_shlibs(&mut file)?;
+            }
+            ControlTarEntryType::Other(name) => {
+                visitor.visit_other_control_file(&name, &mut file)?;
+            }
+            ControlTarEntryType::Directory => {}
         }
     }
-    map
+    control.ok_or_else(|| DebError::MalformedControl("missing control file".to_string()))
 }
 
-fn visit_data_tar_files(ar_entry: ar::Entry<impl std::io::Read>, visitor: &mut impl DebVisitor) {
-    let reader = lzma::reader::LzmaReader::new_decompressor(ar_entry).expect("Error decompressing");
-    let mut control_tar = tar::Archive::new(reader);
-    for file in control_tar.entries().unwrap() {
-        let mut file = file.unwrap();
-        visitor.visit_file(&mut file);
+fn visit_data_tar_files(
+    compression: Compression,
+    ar_entry: ar::Entry<impl std::io::Read>,
+    visitor: &mut impl DebVisitor,
+) -> Result<(), DebError> {
+    let mut data_tar = tar::Archive::new(compression.decompress(ar_entry)?);
+    for file in data_tar.entries().map_err(DebError::from_tar)? {
+        let mut file = file.map_err(DebError::from_tar)?;
+        visitor.visit_file(&mut file)?;
     }
+    Ok(())
 }
 
-pub fn visit_files(reader: &mut impl std::io::Read, visitor: &mut impl DebVisitor) {
+pub fn visit_files(
+    reader: &mut impl std::io::Read,
+    visitor: &mut impl DebVisitor,
+) -> Result<(), DebError> {
     let mut archive = ar::Archive::new(reader);
     while let Some(entry_result) = archive.next_entry() {
-        let entry = entry_result.unwrap();
-        let mut control_tar = false;
-        let mut data_tar = false;
-        let mut control_tar_xz = false;
+        let entry = entry_result.map_err(DebError::BadArHeader)?;
+
+        let entry_name = String::from_utf8_lossy(entry.header().identifier()).into_owned();
+        let member = ArMember::from_name(&entry_name).ok_or(DebError::UnknownMember(entry_name))?;
+
+        match member {
+            ArMember::ControlTar(compression) => {
+                let control = parse_control_ar_entry(compression, entry, visitor)?;
+                visitor.visit_control(control)?;
+            }
+            ArMember::DataTar(compression) => {
+                visit_data_tar_files(compression, entry, visitor)?;
+            }
+            ArMember::DebianBinary | ArMember::Ignored => {}
+        }
+    }
+    Ok(())
+}
+
+/// Normalize a path from data.tar, md5sums or conffiles to the form used in md5sums.
+fn md5sums_path(path: &str) -> &str {
+    path.trim_start_matches("./").trim_start_matches('/')
+}
+
+/// Result of checking the files in data.tar against the md5sums control file.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Md5sumsReport {
+    /// If the package has an md5sums control file at all.
+    pub has_md5sums: bool,
+    /// Files listed in md5sums but not present in data.tar.
+    pub missing: Vec<String>,
+    /// Regular files in data.tar which are neither listed in md5sums nor conffiles.
+    pub extra: Vec<String>,
+    /// Files whose digest does not match the one in md5sums.
+    pub mismatched: Vec<String>,
+}
+
+impl Md5sumsReport {
+    pub fn is_ok(&self) -> bool {
+        self.has_md5sums
+            && self.missing.is_empty()
+            && self.extra.is_empty()
+            && self.mismatched.is_empty()
+    }
+}
+
+impl fmt::Display for Md5sumsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.has_md5sums {
+            writeln!(f, "No md5sums control file")?;
+        }
+        for path in &self.missing {
+            writeln!(f, "Missing file: {}", path)?;
+        }
+        for path in &self.extra {
+            writeln!(f, "File not in md5sums: {}", path)?;
+        }
+        for path in &self.mismatched {
+            writeln!(f, "Checksum mismatch: {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Visitor hashing regular files in data.tar while streaming, to compare them
+/// against the md5sums control file. Other visitors can forward the md5sums,
+/// conffiles and file callbacks to it to verify while visiting.
+#[derive(Default)]
+pub struct Md5sumsVerifier {
+    expected: Option<Vec<(String, String)>>,
+    conffiles: Vec<String>,
+    /// Digests of regular files and hard links in data.tar, by md5sums path.
+    actual: HashMap<String, String>,
+}
+
+impl Md5sumsVerifier {
+    pub fn new() -> Md5sumsVerifier {
+        Md5sumsVerifier::default()
+    }
+
+    /// Compare what has been visited and reset the verifier for the next package.
+    pub fn finish(&mut self) -> Md5sumsReport {
+        let verifier = std::mem::take(self);
+        let mut actual = verifier.actual;
+        let mut report = Md5sumsReport {
+            has_md5sums: verifier.expected.is_some(),
+            ..Md5sumsReport::default()
+        };
+
+        for (path, expected_digest) in verifier.expected.unwrap_or_default() {
+            match actual.remove(&path) {
+                None => report.missing.push(path),
+                Some(digest) if !digest.eq_ignore_ascii_case(&expected_digest) => {
+                    report.mismatched.push(path)
+                }
+                Some(_) => {}
+            }
+        }
+        for conffile in &verifier.conffiles {
+            actual.remove(conffile);
+        }
+        report.extra = actual.into_keys().collect();
+        report.extra.sort();
+        report
+    }
+}
+
+impl DebVisitor for Md5sumsVerifier {
+    fn visit_control(&mut self, _: Paragraph) -> Result<(), DebError> {
+        Ok(())
+    }
+
+    fn visit_conffiles(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        self.conffiles = contents
+            .lines()
+            .map(|line| md5sums_path(line.trim()).to_string())
+            .filter(|path| !path.is_empty())
+            .collect();
+        Ok(())
+    }
+
+    fn visit_md5sums(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut expected = Vec::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let (digest, path) = line.split_once(' ').ok_or_else(|| {
+                DebError::MalformedControl(format!("invalid md5sums line '{}'", line))
+            })?;
+            expected.push((
+                md5sums_path(path.trim_start()).to_string(),
+                digest.to_string(),
+            ));
+        }
+        self.expected = Some(expected);
+        Ok(())
+    }
+
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        let path = md5sums_path(&file.path()?.to_string_lossy()).to_string();
+        match file.header().entry_type() {
+            tar::EntryType::Regular => {
+                let mut context = md5::Context::new();
+                io::copy(file, &mut context)?;
+                self.actual.insert(path, format!("{:x}", context.compute()));
+            }
+            tar::EntryType::Link => {
+                let target = match file.link_name()? {
+                    Some(target) => md5sums_path(&target.to_string_lossy()).to_string(),
+                    None => return Ok(()),
+                };
+                if let Some(digest) = self.actual.get(&target).cloned() {
+                    self.actual.insert(path, digest);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Check the files in a deb file against its md5sums control file.
+pub fn verify_md5sums(reader: &mut impl Read) -> Result<Md5sumsReport, DebError> {
+    let mut verifier = Md5sumsVerifier::new();
+    visit_files(reader, &mut verifier)?;
+    Ok(verifier.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compression() {
+        assert_eq!(Ok(Compression::Zstd), "zstd".parse());
+        assert_eq!(Ok(Compression::None), "none".parse());
+        assert!("lzma".parse::<Compression>().is_err());
+        assert!("gz".parse::<Compression>().is_err());
+    }
+
+    #[test]
+    fn test_ar_member_from_name() {
+        for (name, expected) in &[
+            ("data.tar", Compression::None),
+            ("data.tar.gz", Compression::Gzip),
+            ("data.tar.xz", Compression::Xz),
+            ("data.tar.lzma", Compression::Lzma),
+            ("data.tar.bz2", Compression::Bzip2),
+            ("data.tar.zst", Compression::Zstd),
+            ("data.tar.zst/", Compression::Zstd),
+        ] {
+            match ArMember::from_name(name) {
+                Some(ArMember::DataTar(compression)) => assert_eq!(*expected, compression),
+                _ => panic!("{} not recognised as data.tar", name),
+            }
+        }
+
+        assert!(matches!(
+            ArMember::from_name("control.tar.zst"),
+            Some(ArMember::ControlTar(Compression::Zstd))
+        ));
+        assert!(matches!(
+            ArMember::from_name("debian-binary"),
+            Some(ArMember::DebianBinary)
+        ));
+        assert!(matches!(
+            ArMember::from_name("_gpgorigin"),
+            Some(ArMember::Ignored)
+        ));
+        assert!(ArMember::from_name("control.tar.bz2").is_none());
+        assert!(ArMember::from_name("data.tar.lz4").is_none());
+        assert!(ArMember::from_name("payload").is_none());
+    }
+
+    struct NoopVisitor;
 
-        let entry_name = std::str::from_utf8(entry.header().identifier()).unwrap();
-        if "control.tar.gz" == entry_name || "control.tar.xz" == entry_name {
-            control_tar = true;
-            control_tar_xz = "control.tar.xz" == entry_name;
-        } else if "data.tar.xz" == entry_name {
-            data_tar = true;
+    impl DebVisitor for NoopVisitor {
+        fn visit_control(&mut self, _: Paragraph) -> Result<(), DebError> {
+            Ok(())
         }
 
-        if control_tar {
-            let control = parse_control_ar_entry(control_tar_xz, entry, visitor);
-            visitor.visit_control(control);
-        } else if data_tar {
-            visit_data_tar_files(entry, visitor);
+        fn visit_file(&mut self, _: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+            Ok(())
         }
     }
+
+    fn ar_with_member(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = ar::Builder::new(Vec::new());
+        let header = ar::Header::new(name.as_bytes().to_vec(), contents.len() as u64);
+        builder.append(&header, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_visit_invalid_deb() {
+        let result = visit_files(&mut &b"not an ar archive"[..], &mut NoopVisitor);
+        assert!(matches!(result, Err(DebError::BadArHeader(_))));
+
+        let deb = ar_with_member("payload", b"");
+        let result = visit_files(&mut &deb[..], &mut NoopVisitor);
+        assert!(matches!(result, Err(DebError::UnknownMember(name)) if name == "payload"));
+
+        let deb = ar_with_member("data.tar.xz", b"garbage");
+        let result = visit_files(&mut &deb[..], &mut NoopVisitor);
+        assert!(matches!(result, Err(DebError::Decompression(_))));
+
+        let deb = ar_with_member("data.tar", &[1; 512]);
+        let result = visit_files(&mut &deb[..], &mut NoopVisitor);
+        assert!(matches!(result, Err(DebError::Tar(_))));
+    }
+
+    #[derive(Default)]
+    struct ControlFilesVisitor {
+        visited: Vec<String>,
+    }
+
+    impl ControlFilesVisitor {
+        fn record(&mut self, name: &str, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            self.visited
+                .push(format!("{}={}", name, contents.trim_end()));
+            Ok(())
+        }
+    }
+
+    impl DebVisitor for ControlFilesVisitor {
+        fn visit_control(&mut self, _: Paragraph) -> Result<(), DebError> {
+            self.visited.push("control".to_string());
+            Ok(())
+        }
+
+        fn visit_maintainer_script(
+            &mut self,
+            script: MaintainerScript,
+            file: &mut tar::Entry<impl Read>,
+        ) -> Result<(), DebError> {
+            self.record(script.name(), file)
+        }
+
+        fn visit_triggers(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+            self.record("triggers", file)
+        }
+
+        fn visit_other_control_file(
+            &mut self,
+            name: &str,
+            file: &mut tar::Entry<impl Read>,
+        ) -> Result<(), DebError> {
+            self.record(name, file)
+        }
+
+        fn visit_file(&mut self, _: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_visit_control_files() {
+        let staging_dir = tempfile::tempdir().unwrap();
+        let control_dir = staging_dir.path().join("DEBIAN");
+        std::fs::create_dir(&control_dir).unwrap();
+        for (name, contents) in &[
+            ("control", "Package: a\nVersion: 1\nArchitecture: all\n"),
+            ("postinst", "#!/bin/sh"),
+            ("prerm", "#!/bin/sh -e"),
+            ("symbols", "libfoo.so"),
+            ("triggers", "interest-noawait /usr/share/fonts"),
+        ] {
+            std::fs::write(control_dir.join(name), contents).unwrap();
+        }
+        let mut deb = Vec::new();
+        crate::deb_builder::build_deb(staging_dir.path(), Compression::Gzip, &mut deb).unwrap();
+
+        let mut visitor = ControlFilesVisitor::default();
+        visit_files(&mut &deb[..], &mut visitor).unwrap();
+        assert_eq!(
+            vec![
+                "postinst=#!/bin/sh",
+                "prerm=#!/bin/sh -e",
+                "symbols=libfoo.so",
+                "triggers=interest-noawait /usr/share/fonts",
+                "control",
+            ],
+            visitor.visited
+        );
+    }
+
+    fn tar_with_files(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_verify_md5sums() {
+        let control_tar = tar_with_files(&[
+            ("control", "Package: a\nVersion: 1\nArchitecture: all\n"),
+            ("conffiles", "/d\n"),
+            (
+                "md5sums",
+                "0cc175b9c0f1b6a831c399e269772661  a\n\
+                 00000000000000000000000000000000  b\n\
+                 4a8a08f09d37b73795649038408b5f33  c\n",
+            ),
+        ]);
+        let data_tar = tar_with_files(&[("a", "a"), ("b", "b"), ("d", "d"), ("e", "e")]);
+        let mut deb = Vec::new();
+        crate::deb_builder::write_deb(&mut deb, Compression::None, &control_tar, &data_tar)
+            .unwrap();
+
+        let report = verify_md5sums(&mut &deb[..]).unwrap();
+        assert_eq!(
+            Md5sumsReport {
+                has_md5sums: true,
+                missing: vec!["c".to_string()],
+                extra: vec!["e".to_string()],
+                mismatched: vec!["b".to_string()],
+            },
+            report
+        );
+        assert!(!report.is_ok());
+    }
 }
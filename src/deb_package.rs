@@ -0,0 +1,288 @@
+//! An owned in-memory model of a deb file, for tools which do not need to
+//! stream the package through a `DebVisitor`.
+use crate::deb822::Paragraph;
+use crate::deb_file::{visit_files, DebError, DebVisitor, MaintainerScript};
+use std::io::{self, Read};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    HardLink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Other,
+}
+
+/// An entry in data.tar.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebFile {
+    /// The path as stored in data.tar, normally starting with `./`.
+    pub path: String,
+    pub file_type: FileType,
+    pub mode: u32,
+    pub owner: String,
+    pub group: String,
+    pub size: u64,
+    pub mtime: u64,
+    /// The target of a symlink or hard link.
+    pub link_target: Option<String>,
+    /// The hex encoded md5 digest of a regular file.
+    pub md5: Option<String>,
+    /// The contents of a regular file, unless skipped when reading.
+    pub contents: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOptions {
+    /// If the contents of regular files in data.tar should be kept in memory.
+    pub file_contents: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            file_contents: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebPackage {
+    pub control: Paragraph,
+    pub conffiles: Vec<String>,
+    pub maintainer_scripts: Vec<(MaintainerScript, Vec<u8>)>,
+    /// Other control.tar members, such as md5sums, triggers and shlibs, by name.
+    pub control_files: Vec<(String, Vec<u8>)>,
+    pub files: Vec<DebFile>,
+}
+
+fn read_contents(file: &mut tar::Entry<impl Read>) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+struct DebPackageVisitor {
+    options: ReadOptions,
+    control: Option<Paragraph>,
+    conffiles: Vec<String>,
+    maintainer_scripts: Vec<(MaintainerScript, Vec<u8>)>,
+    control_files: Vec<(String, Vec<u8>)>,
+    files: Vec<DebFile>,
+}
+
+impl DebVisitor for DebPackageVisitor {
+    fn visit_control(&mut self, control: Paragraph) -> Result<(), DebError> {
+        self.control = Some(control);
+        Ok(())
+    }
+
+    fn visit_conffiles(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        let contents = read_contents(file)?;
+        self.conffiles = String::from_utf8_lossy(&contents)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        Ok(())
+    }
+
+    fn visit_maintainer_script(
+        &mut self,
+        script: MaintainerScript,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        self.maintainer_scripts.push((script, read_contents(file)?));
+        Ok(())
+    }
+
+    fn visit_md5sums(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.visit_other_control_file("md5sums", file)
+    }
+
+    fn visit_triggers(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.visit_other_control_file("triggers", file)
+    }
+
+    fn visit_shlibs(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        self.visit_other_control_file("shlibs", file)
+    }
+
+    fn visit_other_control_file(
+        &mut self,
+        name: &str,
+        file: &mut tar::Entry<impl Read>,
+    ) -> Result<(), DebError> {
+        self.control_files
+            .push((name.to_string(), read_contents(file)?));
+        Ok(())
+    }
+
+    fn visit_file(&mut self, file: &mut tar::Entry<impl Read>) -> Result<(), DebError> {
+        let header = file.header();
+        let file_type = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => FileType::Regular,
+            tar::EntryType::Directory => FileType::Directory,
+            tar::EntryType::Symlink => FileType::Symlink,
+            tar::EntryType::Link => FileType::HardLink,
+            tar::EntryType::Char => FileType::CharDevice,
+            tar::EntryType::Block => FileType::BlockDevice,
+            tar::EntryType::Fifo => FileType::Fifo,
+            _ => FileType::Other,
+        };
+        let name_or_id = |name: Result<Option<&str>, _>, id: io::Result<u64>| match name {
+            Ok(Some(name)) if !name.is_empty() => name.to_string(),
+            _ => id.map(|id| id.to_string()).unwrap_or_default(),
+        };
+        let mut deb_file = DebFile {
+            path: file.path()?.to_string_lossy().into_owned(),
+            file_type,
+            mode: header.mode()?,
+            owner: name_or_id(header.username(), header.uid()),
+            group: name_or_id(header.groupname(), header.gid()),
+            size: header.size()?,
+            mtime: header.mtime()?,
+            link_target: file
+                .link_name()?
+                .map(|target| target.to_string_lossy().into_owned()),
+            md5: None,
+            contents: None,
+        };
+
+        if file_type == FileType::Regular {
+            if self.options.file_contents {
+                let contents = read_contents(file)?;
+                deb_file.md5 = Some(format!("{:x}", md5::compute(&contents)));
+                deb_file.contents = Some(contents);
+            } else {
+                let mut context = md5::Context::new();
+                io::copy(file, &mut context)?;
+                deb_file.md5 = Some(format!("{:x}", context.compute()));
+            }
+        }
+        self.files.push(deb_file);
+        Ok(())
+    }
+}
+
+impl DebPackage {
+    /// Read a whole deb file, keeping the contents of all files in memory.
+    pub fn read(reader: &mut impl Read) -> Result<DebPackage, DebError> {
+        DebPackage::read_with_options(reader, ReadOptions::default())
+    }
+
+    pub fn read_with_options(
+        reader: &mut impl Read,
+        options: ReadOptions,
+    ) -> Result<DebPackage, DebError> {
+        let mut visitor = DebPackageVisitor {
+            options,
+            control: None,
+            conffiles: Vec::new(),
+            maintainer_scripts: Vec::new(),
+            control_files: Vec::new(),
+            files: Vec::new(),
+        };
+        visit_files(reader, &mut visitor)?;
+        Ok(DebPackage {
+            control: visitor
+                .control
+                .ok_or_else(|| DebError::MalformedControl("missing control.tar".to_string()))?,
+            conffiles: visitor.conffiles,
+            maintainer_scripts: visitor.maintainer_scripts,
+            control_files: visitor.control_files,
+            files: visitor.files,
+        })
+    }
+
+    pub fn maintainer_script(&self, script: MaintainerScript) -> Option<&[u8]> {
+        self.maintainer_scripts
+            .iter()
+            .find(|(s, _)| *s == script)
+            .map(|(_, contents)| &contents[..])
+    }
+
+    pub fn control_file(&self, name: &str) -> Option<&[u8]> {
+        self.control_files
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, contents)| &contents[..])
+    }
+
+    /// Look up a file by path, with or without the leading `./` or `/`.
+    pub fn file(&self, path: &str) -> Option<&DebFile> {
+        fn normalize(path: &str) -> &str {
+            path.trim_start_matches("./").trim_start_matches('/')
+        }
+        let path = normalize(path);
+        self.files.iter().find(|file| normalize(&file.path) == path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deb_builder::build_deb;
+    use crate::deb_file::Compression;
+    use std::fs;
+
+    #[test]
+    fn test_read_package() {
+        let staging_dir = tempfile::tempdir().unwrap();
+        let staging_path = staging_dir.path();
+        fs::create_dir_all(staging_path.join("DEBIAN")).unwrap();
+        fs::create_dir_all(staging_path.join("etc")).unwrap();
+        fs::write(
+            staging_path.join("DEBIAN/control"),
+            "Package: a\nVersion: 1\nArchitecture: all\n",
+        )
+        .unwrap();
+        fs::write(staging_path.join("DEBIAN/conffiles"), "/etc/a.conf\n").unwrap();
+        fs::write(staging_path.join("DEBIAN/postrm"), "#!/bin/sh\n").unwrap();
+        fs::write(staging_path.join("etc/a.conf"), "a").unwrap();
+        std::os::unix::fs::symlink("a.conf", staging_path.join("etc/b.conf")).unwrap();
+        let mut deb = Vec::new();
+        build_deb(staging_path, Compression::Zstd, &mut deb).unwrap();
+
+        let package = DebPackage::read(&mut &deb[..]).unwrap();
+        assert_eq!("a", &package.control["Package"]);
+        assert_eq!(vec!["/etc/a.conf"], package.conffiles);
+        assert_eq!(
+            Some(&b"#!/bin/sh\n"[..]),
+            package.maintainer_script(MaintainerScript::Postrm)
+        );
+        assert_eq!(None, package.maintainer_script(MaintainerScript::Preinst));
+        assert_eq!(
+            Some(&b"0cc175b9c0f1b6a831c399e269772661  etc/a.conf\n"[..]),
+            package.control_file("md5sums")
+        );
+
+        let conf = package.file("/etc/a.conf").unwrap();
+        assert_eq!(FileType::Regular, conf.file_type);
+        assert_eq!("root", conf.owner);
+        assert_eq!(1, conf.size);
+        assert_eq!(
+            Some("0cc175b9c0f1b6a831c399e269772661"),
+            conf.md5.as_deref()
+        );
+        assert_eq!(Some(&b"a"[..]), conf.contents.as_deref());
+        let link = package.file("etc/b.conf").unwrap();
+        assert_eq!(FileType::Symlink, link.file_type);
+        assert_eq!(Some("a.conf"), link.link_target.as_deref());
+
+        let options = ReadOptions {
+            file_contents: false,
+        };
+        let package = DebPackage::read_with_options(&mut &deb[..], options).unwrap();
+        let conf = package.file("./etc/a.conf").unwrap();
+        assert_eq!(None, conf.contents);
+        assert_eq!(
+            Some("0cc175b9c0f1b6a831c399e269772661"),
+            conf.md5.as_deref()
+        );
+    }
+}
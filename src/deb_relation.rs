@@ -0,0 +1,291 @@
+//! Package relationship fields such as Depends, Pre-Depends, Provides,
+//! Conflicts and Breaks, like `libc (>= 2.3) | libc-compat, foo:any [!i686]`.
+//!
+//! See https://www.debian.org/doc/debian-policy/ch-relationships.html
+use crate::deb_version::{DebVersion, VersionError};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RelationError(String);
+
+impl fmt::Display for RelationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid relation: {}", self.0)
+    }
+}
+
+impl std::error::Error for RelationError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionOp {
+    /// `<<`
+    Earlier,
+    /// `<=`, or the obsolete `<`
+    EarlierOrEqual,
+    /// `=`
+    Equal,
+    /// `>=`, or the obsolete `>`
+    LaterOrEqual,
+    /// `>>`
+    Later,
+}
+
+impl VersionOp {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            VersionOp::Earlier => "<<",
+            VersionOp::EarlierOrEqual => "<=",
+            VersionOp::Equal => "=",
+            VersionOp::LaterOrEqual => ">=",
+            VersionOp::Later => ">>",
+        }
+    }
+
+    /// If `version` stands in this relation to `constraint`.
+    pub fn matches(self, version: &DebVersion, constraint: &DebVersion) -> bool {
+        match self {
+            VersionOp::Earlier => version < constraint,
+            VersionOp::EarlierOrEqual => version <= constraint,
+            VersionOp::Equal => version == constraint,
+            VersionOp::LaterOrEqual => version >= constraint,
+            VersionOp::Later => version > constraint,
+        }
+    }
+}
+
+/// A single package in a relationship field, with an optional version
+/// constraint and architecture restrictions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Relation {
+    pub name: String,
+    /// An architecture qualifier such as `any` in `python:any`.
+    pub arch_qualifier: Option<String>,
+    pub version: Option<(VersionOp, DebVersion)>,
+    /// Architecture restrictions such as `amd64` and `!i686` in
+    /// `[amd64 !i686]`, empty if there are none.
+    pub architectures: Vec<String>,
+}
+
+impl Relation {
+    /// If a package with the given version satisfies the version constraint.
+    pub fn matches_version(&self, version: &DebVersion) -> bool {
+        match &self.version {
+            Some((op, constraint)) => op.matches(version, constraint),
+            None => true,
+        }
+    }
+
+    /// If the relation applies on an architecture, given its architecture
+    /// restrictions.
+    pub fn applies_to(&self, arch: &str) -> bool {
+        if self.architectures.is_empty() {
+            return true;
+        }
+        let negated = self.architectures[0].starts_with('!');
+        let listed = self
+            .architectures
+            .iter()
+            .any(|a| a.trim_start_matches('!') == arch);
+        listed != negated
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c))
+}
+
+impl FromStr for Relation {
+    type Err = RelationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: &str| RelationError(format!("'{}': {}", s.trim(), message));
+        let mut rest = s.trim();
+
+        let mut architectures = Vec::new();
+        if let Some(start) = rest.find('[') {
+            let list = rest[start + 1..]
+                .strip_suffix(']')
+                .ok_or_else(|| error("unterminated architecture list"))?;
+            architectures = list.split_whitespace().map(String::from).collect();
+            let negated = architectures.iter().filter(|a| a.starts_with('!')).count();
+            if architectures.is_empty() || (negated != 0 && negated != architectures.len()) {
+                return Err(error("invalid architecture list"));
+            }
+            rest = rest[..start].trim_end();
+        }
+
+        let mut version = None;
+        if let Some(start) = rest.find('(') {
+            let constraint = rest[start + 1..]
+                .trim_end()
+                .strip_suffix(')')
+                .ok_or_else(|| error("unterminated version constraint"))?
+                .trim();
+            let (op, constraint) = [
+                ("<<", VersionOp::Earlier),
+                ("<=", VersionOp::EarlierOrEqual),
+                (">=", VersionOp::LaterOrEqual),
+                (">>", VersionOp::Later),
+                ("=", VersionOp::Equal),
+                ("<", VersionOp::EarlierOrEqual),
+                (">", VersionOp::LaterOrEqual),
+            ]
+            .iter()
+            .find_map(|(prefix, op)| constraint.strip_prefix(prefix).map(|v| (*op, v)))
+            .ok_or_else(|| error("missing version operator"))?;
+            let constraint = constraint
+                .parse()
+                .map_err(|e: VersionError| error(&e.to_string()))?;
+            version = Some((op, constraint));
+            rest = rest[..start].trim_end();
+        }
+
+        let (name, arch_qualifier) = match rest.split_once(':') {
+            Some((name, arch)) => (name, Some(arch.to_string())),
+            None => (rest, None),
+        };
+        if !is_valid_name(name) {
+            return Err(error("invalid package name"));
+        }
+        if arch_qualifier
+            .as_deref()
+            .is_some_and(|arch| !is_valid_name(arch))
+        {
+            return Err(error("invalid architecture qualifier"));
+        }
+        Ok(Relation {
+            name: name.to_string(),
+            arch_qualifier,
+            version,
+            architectures,
+        })
+    }
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(arch) = &self.arch_qualifier {
+            write!(f, ":{}", arch)?;
+        }
+        if let Some((op, version)) = &self.version {
+            write!(f, " ({} {})", op.as_str(), version)?;
+        }
+        if !self.architectures.is_empty() {
+            write!(f, " [{}]", self.architectures.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A parsed relationship field: a list of requirements which must all be
+/// met, each of which is met by any one of its alternatives.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Relations(pub Vec<Vec<Relation>>);
+
+impl FromStr for Relations {
+    type Err = RelationError;
+
+    /// Parse a field value, which may span several lines. Empty entries,
+    /// such as after a trailing comma, are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| entry.split('|').map(str::parse).collect())
+            .collect::<Result<_, _>>()
+            .map(Relations)
+    }
+}
+
+impl fmt::Display for Relations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, alternatives) in self.0.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            for (j, relation) in alternatives.iter().enumerate() {
+                if j != 0 {
+                    write!(f, " | ")?;
+                }
+                write!(f, "{}", relation)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let relations: Relations = "libc (>= 2.3) | libc-compat,\n python3:any [!i686 !arm], dvtm,"
+            .parse()
+            .unwrap();
+        assert_eq!(3, relations.0.len());
+        assert_eq!(2, relations.0[0].len());
+
+        let libc = &relations.0[0][0];
+        assert_eq!("libc", libc.name);
+        assert_eq!(
+            Some((VersionOp::LaterOrEqual, "2.3".parse().unwrap())),
+            libc.version
+        );
+        assert!(libc.matches_version(&"2.10".parse().unwrap()));
+        assert!(!libc.matches_version(&"2.3~rc1".parse().unwrap()));
+        assert!(relations.0[0][1].matches_version(&"0.1".parse().unwrap()));
+
+        let python = &relations.0[1][0];
+        assert_eq!(Some("any"), python.arch_qualifier.as_deref());
+        assert!(python.applies_to("aarch64"));
+        assert!(!python.applies_to("arm"));
+        assert!("a [aarch64]"
+            .parse::<Relation>()
+            .unwrap()
+            .applies_to("aarch64"));
+        assert!(!"a [aarch64]".parse::<Relation>().unwrap().applies_to("arm"));
+
+        assert_eq!(
+            "libc (>= 2.3) | libc-compat, python3:any [!i686 !arm], dvtm",
+            relations.to_string()
+        );
+        assert_eq!(
+            "a (<= 1), b (>= 1)",
+            "a(<1),b (> 1)".parse::<Relations>().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for field in &[
+            "",
+            "libutil, dvtm",
+            "a (<< 1:2.0-1), b (= 1.0~rc1) | c:native (>> 0.1) [arm aarch64]",
+            "python (<= 3.8) [!x86_64]",
+        ] {
+            assert_eq!(*field, field.parse::<Relations>().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        for invalid in &[
+            "a |",
+            "A",
+            "a (>= )",
+            "a (1.0)",
+            "a (>= 1.0",
+            "a [arm",
+            "a [arm !i686]",
+            "a []",
+            "a:",
+        ] {
+            assert!(invalid.parse::<Relations>().is_err(), "{}", invalid);
+        }
+    }
+}
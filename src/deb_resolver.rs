@@ -0,0 +1,465 @@
+//! Resolution of the set of packages needed to install some root packages,
+//! following Depends and Pre-Depends through the packages of an index.
+use crate::apt_repo::{Package, PackageIndex};
+use crate::deb_relation::{Relation, RelationError, Relations};
+use crate::deb_version::DebVersion;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum ResolveError {
+    /// A relationship field of a package could not be parsed.
+    InvalidField {
+        package: String,
+        field: &'static str,
+        error: RelationError,
+    },
+    /// No package can satisfy a requirement.
+    Unsatisfiable {
+        requirement: String,
+        /// The chain of packages leading to the requirement, starting with
+        /// the one declaring it. Empty for a root requirement.
+        required_by: Vec<String>,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResolveError::InvalidField {
+                package,
+                field,
+                error,
+            } => write!(f, "{} field of {}: {}", field, package, error),
+            ResolveError::Unsatisfiable {
+                requirement,
+                required_by,
+                reason,
+            } => {
+                write!(f, "cannot satisfy '{}'", requirement)?;
+                if !required_by.is_empty() {
+                    write!(f, " required by {}", required_by.join(" <- "))?;
+                }
+                write!(f, ": {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// A package selected by `resolve`, and the package whose dependency caused
+/// it to be selected, if it was not a root or essential package.
+pub struct ResolvedPackage<'a> {
+    pub package: &'a Package,
+    pub required_by: Option<String>,
+}
+
+fn is_essential(package: &Package) -> bool {
+    package.fields.get("Essential") == Some("yes")
+}
+
+struct Resolver<'a> {
+    index: &'a PackageIndex,
+    arch: &'a str,
+    /// The packages providing each virtual package, by name, with the
+    /// provided version if any.
+    providers: HashMap<String, Vec<(&'a Package, Option<DebVersion>)>>,
+    selected: BTreeMap<String, ResolvedPackage<'a>>,
+    queue: VecDeque<&'a Package>,
+}
+
+impl<'a> Resolver<'a> {
+    fn new(index: &'a PackageIndex, arch: &'a str) -> Resolver<'a> {
+        let mut providers: HashMap<_, Vec<_>> = HashMap::new();
+        let mut names: Vec<_> = index.names().collect();
+        names.sort_unstable();
+        for name in names {
+            let package = index.newest(name).expect("Listed names have a package");
+            // An invalid Provides field only matters if the package is needed,
+            // where it is reported when its dependencies are resolved.
+            for provided in package.relations("Provides").unwrap_or_default().0 {
+                for relation in provided {
+                    let version = relation.version.map(|(_, version)| version);
+                    providers
+                        .entry(relation.name)
+                        .or_default()
+                        .push((package, version));
+                }
+            }
+        }
+        Resolver {
+            index,
+            arch,
+            providers,
+            selected: BTreeMap::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    fn providers(&self, relation: &Relation) -> impl Iterator<Item = &'a Package> + '_ {
+        let relation = relation.clone();
+        self.providers
+            .get(&relation.name)
+            .into_iter()
+            .flatten()
+            .filter(move |(_, version)| {
+                relation.version.is_none()
+                    || version
+                        .as_ref()
+                        .is_some_and(|version| relation.matches_version(version))
+            })
+            .map(|(package, _)| *package)
+    }
+
+    fn is_selected(&self, package: &Package) -> bool {
+        self.selected
+            .get(&package.fields["Package"])
+            .is_some_and(|selected| std::ptr::eq(selected.package, package))
+    }
+
+    /// If a selected package, or a package provided by one, satisfies a
+    /// relation.
+    fn is_satisfied(&self, relation: &Relation) -> bool {
+        let selected_version = self
+            .selected
+            .get(&relation.name)
+            .map(|selected| &selected.package.version);
+        selected_version.is_some_and(|version| relation.matches_version(version))
+            || self.providers(relation).any(|p| self.is_selected(p))
+    }
+
+    /// The package to select for an alternative, or why there is none.
+    fn candidate(&self, relation: &Relation) -> Result<&'a Package, String> {
+        let package = self
+            .index
+            .newest_matching(&relation.name, |version| relation.matches_version(version))
+            .or_else(|| self.providers(relation).next());
+        match package {
+            Some(package) => {
+                let name = &package.fields["Package"];
+                match self.selected.get(name) {
+                    Some(selected) if !std::ptr::eq(selected.package, package) => Err(format!(
+                        "{} {} is already selected",
+                        name, selected.package.version
+                    )),
+                    _ => Ok(package),
+                }
+            }
+            None => {
+                let versions = self.index.versions(&relation.name);
+                if versions.is_empty() {
+                    Err(format!("no package {} is available", relation.name))
+                } else {
+                    let versions: Vec<_> = versions.iter().map(|p| p.version.to_string()).collect();
+                    Err(format!(
+                        "{} is available in version {}",
+                        relation.name,
+                        versions.join(", ")
+                    ))
+                }
+            }
+        }
+    }
+
+    fn required_by_chain(&self, name: Option<&String>) -> Vec<String> {
+        let mut chain = Vec::new();
+        let mut name = name.cloned();
+        while let Some(current) = name {
+            name = self.selected[&current].required_by.clone();
+            chain.push(current);
+        }
+        chain
+    }
+
+    /// Select a package satisfying one of the alternatives, unless one is
+    /// already satisfied.
+    fn require(
+        &mut self,
+        alternatives: &[Relation],
+        required_by: Option<&String>,
+    ) -> Result<(), ResolveError> {
+        let alternatives: Vec<_> = alternatives
+            .iter()
+            .filter(|relation| relation.applies_to(self.arch))
+            .collect();
+        if alternatives.is_empty() || alternatives.iter().any(|r| self.is_satisfied(r)) {
+            return Ok(());
+        }
+        let mut reasons = Vec::new();
+        for relation in &alternatives {
+            match self.candidate(relation) {
+                Ok(package) => {
+                    self.selected.insert(
+                        package.fields["Package"].to_string(),
+                        ResolvedPackage {
+                            package,
+                            required_by: required_by.cloned(),
+                        },
+                    );
+                    self.queue.push_back(package);
+                    return Ok(());
+                }
+                Err(reason) => reasons.push(reason),
+            }
+        }
+        Err(ResolveError::Unsatisfiable {
+            requirement: Relations(vec![alternatives.into_iter().cloned().collect()]).to_string(),
+            required_by: self.required_by_chain(required_by),
+            reason: reasons.join(", and "),
+        })
+    }
+
+    fn resolve_queue(&mut self) -> Result<(), ResolveError> {
+        while let Some(package) = self.queue.pop_front() {
+            let name = package.fields["Package"].to_string();
+            for &field in &["Pre-Depends", "Depends"] {
+                let relations =
+                    package
+                        .relations(field)
+                        .map_err(|error| ResolveError::InvalidField {
+                            package: name.clone(),
+                            field,
+                            error,
+                        })?;
+                for alternatives in &relations.0 {
+                    self.require(alternatives, Some(&name))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve the packages needed to install the root packages and all
+/// Essential packages on an architecture, following Depends and Pre-Depends.
+///
+/// The newest version satisfying a relation is selected, trying alternatives
+/// in order and using real packages before virtual ones provided by others.
+/// Roots are relations such as `curl` or `curl (>= 7.70)`. The result is
+/// sorted by package name.
+pub fn resolve<'a>(
+    index: &'a PackageIndex,
+    roots: &[String],
+    arch: &'a str,
+) -> Result<Vec<ResolvedPackage<'a>>, ResolveError> {
+    let mut resolver = Resolver::new(index, arch);
+    let mut names: Vec<_> = index.names().collect();
+    names.sort_unstable();
+    let essential = names
+        .into_iter()
+        .filter_map(|name| index.newest(name))
+        .filter(|package| is_essential(package))
+        .map(|package| package.fields["Package"].to_string());
+    for root in roots.iter().cloned().chain(essential) {
+        let relations: Relations = root.parse().map_err(|error| ResolveError::InvalidField {
+            package: root.clone(),
+            field: "root",
+            error,
+        })?;
+        for alternatives in &relations.0 {
+            resolver.require(alternatives, None)?;
+        }
+        resolver.resolve_queue()?;
+    }
+    Ok(resolver.selected.into_values().collect())
+}
+
+/// Check that the Depends and Pre-Depends of the newest version of each
+/// package in an index can be satisfied by a package in the index, returning
+/// a problem for each one that cannot. Unlike `resolve`, each package is
+/// checked on its own, without selecting a consistent set of versions.
+pub fn check_dependencies(index: &PackageIndex, arch: &str) -> Vec<ResolveError> {
+    let resolver = Resolver::new(index, arch);
+    let mut names: Vec<_> = index.names().collect();
+    names.sort_unstable();
+    let mut problems = Vec::new();
+    for package in names.into_iter().filter_map(|name| index.newest(name)) {
+        let name = &package.fields["Package"];
+        for &field in &["Pre-Depends", "Depends"] {
+            let relations = match package.relations(field) {
+                Ok(relations) => relations,
+                Err(error) => {
+                    problems.push(ResolveError::InvalidField {
+                        package: name.to_string(),
+                        field,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            for alternatives in relations.0 {
+                let alternatives: Vec<_> = alternatives
+                    .into_iter()
+                    .filter(|relation| relation.applies_to(arch))
+                    .collect();
+                let mut reasons = Vec::new();
+                for relation in &alternatives {
+                    match resolver.candidate(relation) {
+                        Ok(_) => break,
+                        Err(reason) => reasons.push(reason),
+                    }
+                }
+                if !alternatives.is_empty() && reasons.len() == alternatives.len() {
+                    problems.push(ResolveError::Unsatisfiable {
+                        requirement: Relations(vec![alternatives]).to_string(),
+                        required_by: vec![name.to_string()],
+                        reason: reasons.join(", and "),
+                    });
+                }
+            }
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apt_repo::RepoSource;
+    use crate::deb822::Paragraphs;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    fn index(packages: &str) -> PackageIndex {
+        let source = Arc::new(RepoSource::default());
+        let mut index = PackageIndex::new();
+        for fields in Paragraphs::new(Cursor::new(packages)) {
+            let fields = fields.unwrap();
+            index.insert(Package {
+                version: fields["Version"].parse().unwrap(),
+                fields,
+                source: Arc::clone(&source),
+            });
+        }
+        index
+    }
+
+    const PACKAGES: &str = "Package: curl
+Version: 7.70
+Depends: libcurl (>= 7.70), ca-certificates | ca-bundle, libandroid [arm]
+
+Package: libcurl
+Version: 7.69
+
+Package: libcurl
+Version: 7.70
+Pre-Depends: libssl (>= 1.1)
+
+Package: openssl
+Version: 1.1
+Provides: libssl (= 1.1)
+
+Package: libandroid
+Version: 1
+
+Package: bundle
+Version: 1
+Provides: ca-bundle
+
+Package: bash
+Version: 5.0
+Essential: yes
+Depends: ncurses
+
+Package: ncurses
+Version: 6.2
+";
+
+    fn resolved(index: &PackageIndex, roots: &[&str], arch: &str) -> Vec<String> {
+        let roots: Vec<_> = roots.iter().map(|root| root.to_string()).collect();
+        resolve(index, &roots, arch)
+            .unwrap()
+            .iter()
+            .map(|resolved| {
+                format!(
+                    "{} {}",
+                    &resolved.package.fields["Package"], resolved.package.version
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let index = index(PACKAGES);
+        assert_eq!(
+            vec![
+                "bash 5.0",
+                "bundle 1",
+                "curl 7.70",
+                "libcurl 7.70",
+                "ncurses 6.2",
+                "openssl 1.1"
+            ],
+            resolved(&index, &["curl"], "aarch64")
+        );
+        assert!(resolved(&index, &["curl"], "arm").contains(&"libandroid 1".to_string()));
+        assert_eq!(
+            vec!["bash 5.0", "libcurl 7.69", "ncurses 6.2"],
+            resolved(&index, &["libcurl (<< 7.70)"], "aarch64")
+        );
+
+        let roots = vec!["curl".to_string()];
+        let resolution = resolve(&index, &roots, "aarch64").unwrap();
+        let required_by = |name: &str| {
+            resolution
+                .iter()
+                .find(|resolved| resolved.package.fields["Package"] == *name)
+                .and_then(|resolved| resolved.required_by.as_deref())
+        };
+        assert_eq!(None, required_by("curl"));
+        assert_eq!(Some("libcurl"), required_by("openssl"));
+    }
+
+    #[test]
+    fn test_unsatisfiable() {
+        let packages = PACKAGES.replace("Provides: libssl (= 1.1)", "Provides: libssl (= 1.0)");
+        let index = index(&packages);
+        let roots = vec!["curl".to_string()];
+        let error = resolve(&index, &roots, "aarch64")
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            "cannot satisfy 'libssl (>= 1.1)' required by libcurl <- curl: no package libssl is available",
+            error
+        );
+
+        let roots = vec!["libcurl (= 7.69)".to_string(), "curl".to_string()];
+        let error = resolve(&index, &roots, "aarch64")
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            "cannot satisfy 'libcurl (>= 7.70)' required by curl: libcurl 7.69 is already selected",
+            error
+        );
+
+        let roots = vec!["missing".to_string()];
+        assert!(resolve(&index, &roots, "aarch64").is_err());
+    }
+
+    #[test]
+    fn test_check_dependencies() {
+        assert!(check_dependencies(&index(PACKAGES), "arm").is_empty());
+
+        let packages = PACKAGES.replace("Provides: ca-bundle", "Provides: other");
+        let problems: Vec<_> = check_dependencies(&index(&packages), "arm")
+            .iter()
+            .map(ResolveError::to_string)
+            .collect();
+        assert_eq!(
+            vec![
+                "cannot satisfy 'ca-certificates | ca-bundle' required by curl: \
+                 no package ca-certificates is available, and no package ca-bundle is available"
+            ],
+            problems
+        );
+
+        let packages = PACKAGES.replace("Package: libandroid", "Package: libandroid-support");
+        assert!(check_dependencies(&index(&packages), "aarch64").is_empty());
+        assert_eq!(1, check_dependencies(&index(&packages), "arm").len());
+    }
+}
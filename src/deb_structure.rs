@@ -0,0 +1,288 @@
+//! Validation of the ar container of a deb file against the rules dpkg
+//! enforces, which `deb_file::visit_files` does not check.
+use crate::deb_file::ArMember;
+use std::fmt;
+use std::io::{self, Read};
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const AR_HEADER_SIZE: usize = 60;
+const DEBIAN_BINARY_VERSION: &[u8] = b"2.0\n";
+
+/// A violation of the deb file format found in the ar container.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StructureProblem {
+    /// The file does not start with the ar magic.
+    BadMagic,
+    /// An ar member header is invalid.
+    BadHeader {
+        offset: u64,
+        reason: String,
+    },
+    /// The file ends before the end of a member.
+    Truncated {
+        member: String,
+    },
+    /// Data which is not an ar member follows the last member.
+    TrailingGarbage {
+        offset: u64,
+    },
+    DebianBinaryNotFirst,
+    BadDebianBinaryVersion(String),
+    MissingMember(&'static str),
+    DuplicateMember(String),
+    DataBeforeControl,
+    UnknownMember(String),
+}
+
+impl fmt::Display for StructureProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StructureProblem::BadMagic => write!(f, "not an ar archive"),
+            StructureProblem::BadHeader { offset, reason } => {
+                write!(f, "bad ar header at offset {}: {}", offset, reason)
+            }
+            StructureProblem::Truncated { member } => write!(f, "truncated member '{}'", member),
+            StructureProblem::TrailingGarbage { offset } => {
+                write!(f, "trailing garbage at offset {}", offset)
+            }
+            StructureProblem::DebianBinaryNotFirst => {
+                write!(f, "debian-binary is not the first member")
+            }
+            StructureProblem::BadDebianBinaryVersion(version) => {
+                write!(f, "unsupported debian-binary version '{}'", version)
+            }
+            StructureProblem::MissingMember(name) => write!(f, "missing member '{}'", name),
+            StructureProblem::DuplicateMember(name) => write!(f, "duplicate member '{}'", name),
+            StructureProblem::DataBeforeControl => write!(f, "data.tar before control.tar"),
+            StructureProblem::UnknownMember(name) => write!(f, "unknown member '{}'", name),
+        }
+    }
+}
+
+/// Read into the buffer until it is full or the end of input, returning the bytes read.
+fn read_fully(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Parse the name and size of a common format ar header, as written by dpkg-deb.
+fn parse_header(header: &[u8]) -> Result<(String, u64), String> {
+    if &header[58..60] != b"`\n" {
+        return Err("bad header terminator".to_string());
+    }
+    let field = |range: std::ops::Range<usize>| {
+        std::str::from_utf8(&header[range])
+            .map(str::trim_end)
+            .map_err(|_| "non UTF-8 header".to_string())
+    };
+    let name = field(0..16)?;
+    let name = name.strip_suffix('/').unwrap_or(name);
+    if name.is_empty() || name.contains('/') {
+        return Err(format!("invalid member name '{}'", name));
+    }
+    for (field_name, range, radix) in &[
+        ("mtime", 16..28, 10),
+        ("uid", 28..34, 10),
+        ("gid", 34..40, 10),
+        ("mode", 40..48, 8),
+    ] {
+        let value = field(range.clone())?;
+        if !value.is_empty() && u64::from_str_radix(value, *radix).is_err() {
+            return Err(format!("invalid {} '{}'", field_name, value));
+        }
+    }
+    let size = field(48..58)?;
+    let size = size
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size '{}'", size))?;
+    Ok((name.to_string(), size))
+}
+
+/// Check the ar container of a deb file, returning the problems found.
+///
+/// This enforces that `debian-binary` is the first member and contains
+/// version 2.0, that there is exactly one control.tar followed by exactly one
+/// data.tar, that headers are valid and that nothing follows the last member.
+pub fn validate_structure(reader: &mut impl Read) -> io::Result<Vec<StructureProblem>> {
+    let mut problems = Vec::new();
+
+    let mut magic = [0; 8];
+    if read_fully(reader, &mut magic)? != magic.len() || magic != AR_MAGIC {
+        problems.push(StructureProblem::BadMagic);
+        return Ok(problems);
+    }
+
+    let mut offset = AR_MAGIC.len() as u64;
+    let mut members: Vec<String> = Vec::new();
+    let mut debian_binary = None;
+    loop {
+        let mut header = [0; AR_HEADER_SIZE];
+        let header_read = read_fully(reader, &mut header)?;
+        if header_read == 0 {
+            break;
+        }
+        let parsed = if header_read < AR_HEADER_SIZE {
+            Err("incomplete header".to_string())
+        } else {
+            parse_header(&header)
+        };
+        let (name, size) = match parsed {
+            Ok(parsed) => parsed,
+            Err(reason) => {
+                let all_members_seen = members.iter().any(|m| m.starts_with("data.tar"));
+                problems.push(if all_members_seen {
+                    StructureProblem::TrailingGarbage { offset }
+                } else {
+                    StructureProblem::BadHeader { offset, reason }
+                });
+                break;
+            }
+        };
+
+        // Members are padded to an even size.
+        let padded_size = size + size % 2;
+        let mut contents = Vec::new();
+        let contents_read = if name == "debian-binary" {
+            let read = reader.take(size.min(64)).read_to_end(&mut contents)? as u64;
+            read + io::copy(&mut reader.take(padded_size - read), &mut io::sink())?
+        } else {
+            io::copy(&mut reader.take(padded_size), &mut io::sink())?
+        };
+        if contents_read < size {
+            problems.push(StructureProblem::Truncated { member: name });
+            break;
+        }
+        if name == "debian-binary" && debian_binary.is_none() {
+            debian_binary = Some(contents);
+        }
+        offset += (AR_HEADER_SIZE as u64) + contents_read;
+        members.push(name);
+    }
+
+    let mut debian_binary_index = None;
+    let mut control_index = None;
+    let mut data_index = None;
+    for (index, name) in members.iter().enumerate() {
+        let slot = match ArMember::from_name(name) {
+            Some(ArMember::DebianBinary) => &mut debian_binary_index,
+            Some(ArMember::ControlTar(_)) => &mut control_index,
+            Some(ArMember::DataTar(_)) => &mut data_index,
+            Some(ArMember::Ignored) => continue,
+            None => {
+                problems.push(StructureProblem::UnknownMember(name.clone()));
+                continue;
+            }
+        };
+        if slot.is_some() {
+            problems.push(StructureProblem::DuplicateMember(name.clone()));
+        } else {
+            *slot = Some(index);
+        }
+    }
+
+    match debian_binary_index {
+        None => problems.push(StructureProblem::MissingMember("debian-binary")),
+        Some(0) => {}
+        Some(_) => problems.push(StructureProblem::DebianBinaryNotFirst),
+    }
+    if let Some(version) = debian_binary {
+        if version != DEBIAN_BINARY_VERSION {
+            problems.push(StructureProblem::BadDebianBinaryVersion(
+                String::from_utf8_lossy(&version).trim_end().to_string(),
+            ));
+        }
+    }
+    match (control_index, data_index) {
+        (Some(control), Some(data)) if data < control => {
+            problems.push(StructureProblem::DataBeforeControl)
+        }
+        (None, _) => problems.push(StructureProblem::MissingMember("control.tar")),
+        (_, None) => problems.push(StructureProblem::MissingMember("data.tar")),
+        _ => {}
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deb_builder::write_deb;
+    use crate::deb_file::Compression;
+
+    fn ar_archive(members: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = ar::Builder::new(Vec::new());
+        for (name, contents) in members {
+            let header = ar::Header::new(name.as_bytes().to_vec(), contents.len() as u64);
+            builder.append(&header, *contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_valid_deb() {
+        let mut deb = Vec::new();
+        write_deb(&mut deb, Compression::Xz, &[], &[]).unwrap();
+        assert_eq!(
+            Vec::<StructureProblem>::new(),
+            validate_structure(&mut &deb[..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_order() {
+        let deb = ar_archive(&[
+            ("data.tar", b""),
+            ("debian-binary", b"3.0\n"),
+            ("control.tar", b""),
+            ("control.tar.gz", b""),
+            ("_signature", b""),
+            ("payload", b"x"),
+        ]);
+        assert_eq!(
+            vec![
+                StructureProblem::DuplicateMember("control.tar.gz".to_string()),
+                StructureProblem::UnknownMember("payload".to_string()),
+                StructureProblem::DebianBinaryNotFirst,
+                StructureProblem::BadDebianBinaryVersion("3.0".to_string()),
+                StructureProblem::DataBeforeControl,
+            ],
+            validate_structure(&mut &deb[..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_truncated_and_trailing_garbage() {
+        assert_eq!(
+            vec![StructureProblem::BadMagic],
+            validate_structure(&mut &b"garbage"[..]).unwrap()
+        );
+
+        let mut deb = Vec::new();
+        write_deb(&mut deb, Compression::Xz, &[], &[]).unwrap();
+        let deb_length = deb.len();
+        deb.extend_from_slice(b"garbage");
+        assert_eq!(
+            vec![StructureProblem::TrailingGarbage {
+                offset: deb_length as u64
+            }],
+            validate_structure(&mut &deb[..]).unwrap()
+        );
+
+        deb.truncate(deb_length - 10);
+        let problems = validate_structure(&mut &deb[..]).unwrap();
+        assert!(matches!(
+            &problems[..],
+            [StructureProblem::Truncated { member }, StructureProblem::MissingMember("data.tar")]
+                if member == "data.tar.xz"
+        ));
+    }
+}
@@ -0,0 +1,225 @@
+//! Debian package versions, compared using the rules of dpkg.
+//!
+//! A version has the form `[epoch:]upstream_version[-debian_revision]`. See
+//! https://www.debian.org/doc/debian-policy/ch-controlfields.html#version
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct VersionError(String);
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid version: {}", self.0)
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+#[derive(Clone, Debug)]
+pub struct DebVersion {
+    pub epoch: u32,
+    pub upstream: String,
+    /// The Debian revision, empty if the version has none.
+    pub revision: String,
+}
+
+impl FromStr for DebVersion {
+    type Err = VersionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = |message: &str| Err(VersionError(format!("'{}': {}", s, message)));
+        let version = s.trim();
+        if version.is_empty() {
+            return error("empty version");
+        }
+
+        let (epoch, rest) = match version.split_once(':') {
+            Some((epoch_str, rest)) => match epoch_str.parse() {
+                Ok(epoch) if epoch_str.chars().all(|c| c.is_ascii_digit()) => (epoch, rest),
+                _ => return error("epoch is not a number"),
+            },
+            None => (0, version),
+        };
+        let (upstream, revision) = match rest.rsplit_once('-') {
+            Some((_, "")) => return error("empty revision"),
+            Some((upstream, revision)) => (upstream, revision),
+            None => (rest, ""),
+        };
+
+        if !upstream.starts_with(|c: char| c.is_ascii_digit()) {
+            return error("upstream version does not start with a digit");
+        }
+        let is_valid_char = |c: char| c.is_ascii_alphanumeric() || ".+~".contains(c);
+        if !upstream
+            .chars()
+            .all(|c| is_valid_char(c) || c == '-' || c == ':')
+        {
+            return error("invalid character in upstream version");
+        }
+        if !revision.chars().all(is_valid_char) {
+            return error("invalid character in revision");
+        }
+
+        Ok(DebVersion {
+            epoch,
+            upstream: upstream.to_string(),
+            revision: revision.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for DebVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.epoch != 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+        write!(f, "{}", self.upstream)?;
+        if !self.revision.is_empty() {
+            write!(f, "-{}", self.revision)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sort weight of a non-digit character: letters sort before other
+/// characters, and `~` before anything, even the end of the string.
+fn order(c: Option<u8>) -> i32 {
+    match c {
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+        None => 0,
+    }
+}
+
+/// Compare an upstream version or revision like dpkg's verrevcmp(), by
+/// alternating non-digit parts compared with `order` and digit parts
+/// compared numerically.
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let is_digit = |s: &[u8], k: usize| s.get(k).is_some_and(u8::is_ascii_digit);
+    while i < a.len() || j < b.len() {
+        while (i < a.len() && !is_digit(a, i)) || (j < b.len() && !is_digit(b, j)) {
+            let (ac, bc) = (order(a.get(i).copied()), order(b.get(j).copied()));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while is_digit(a, i) && is_digit(b, j) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        if is_digit(a, i) {
+            return Ordering::Greater;
+        }
+        if is_digit(b, j) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
+}
+
+impl Ord for DebVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| compare_part(&self.upstream, &other.upstream))
+            .then_with(|| compare_part(&self.revision, &other.revision))
+    }
+}
+
+impl PartialOrd for DebVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Versions are equal if dpkg considers them equal, so `1.0` equals `1.00`
+/// and `0:1.0-0`.
+impl PartialEq for DebVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DebVersion {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(s: &str) -> DebVersion {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let parsed = version("1:2.30-4.1");
+        assert_eq!(1, parsed.epoch);
+        assert_eq!("2.30", parsed.upstream);
+        assert_eq!("4.1", parsed.revision);
+        assert_eq!("1:2.30-4.1", parsed.to_string());
+
+        let parsed = version("7.1.2.33-git-2");
+        assert_eq!("7.1.2.33-git", parsed.upstream);
+        assert_eq!("2", parsed.revision);
+        assert_eq!("0.6", version("0:0.6").to_string());
+
+        for invalid in &["", "a1.0", "x:1.0", "1.0-", "1.0_1", "1:", "1.0-1:2"] {
+            assert!(invalid.parse::<DebVersion>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let ascending = [
+            "0.9~rc1",
+            "0.9",
+            "0.9-1",
+            "0.9a",
+            "0.9+1",
+            "0.10",
+            "1.0~~",
+            "1.0~",
+            "1.0",
+            "1.0-0.1",
+            "1.0-1~bpo1",
+            "1.0-1",
+            "1.0-2",
+            "1.0-10",
+            "1.0a",
+            "1.0.1",
+            "1:0.1",
+        ];
+        for pair in ascending.windows(2) {
+            assert!(
+                version(pair[0]) < version(pair[1]),
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert_eq!(version("1.0"), version("1.00"));
+        assert_eq!(version("1.0"), version("0:1.0-0"));
+        assert_ne!(version("1.0"), version("1.0-1"));
+    }
+}
@@ -0,0 +1,29 @@
+extern crate ar;
+extern crate bzip2;
+extern crate libflate;
+extern crate lzma;
+extern crate md5;
+extern crate reqwest;
+extern crate tar;
+extern crate walkdir;
+extern crate zip;
+extern crate zstd;
+
+pub mod apt_repo;
+pub mod cmd_bootstraps;
+pub mod cmd_build_deb;
+pub mod cmd_checkrepo;
+pub mod cmd_debinfo;
+pub mod cmd_extract;
+pub mod cmd_index;
+pub mod cmd_notfound;
+pub mod cmd_package_apk;
+pub mod deb822;
+pub mod deb_builder;
+pub mod deb_file;
+pub mod deb_package;
+pub mod deb_relation;
+pub mod deb_resolver;
+pub mod deb_structure;
+pub mod deb_version;
+pub mod openpgp;
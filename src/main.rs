@@ -1,22 +1,141 @@
-extern crate ar;
-extern crate libflate;
-extern crate lzma;
-extern crate md5;
-extern crate reqwest;
 extern crate structopt;
-extern crate tar;
-extern crate walkdir;
-extern crate zip;
+extern crate termux_packaging;
 
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use structopt::StructOpt;
+use termux_packaging::apt_repo::{
+    DownloadCache, DownloadOptions, ReleaseOptions, RepoError, RepoSource, SourcePriority,
+};
+use termux_packaging::deb_file::Compression;
+use termux_packaging::{
+    cmd_bootstraps, cmd_build_deb, cmd_checkrepo, cmd_debinfo, cmd_extract, cmd_index,
+    cmd_notfound, cmd_package_apk,
+};
 
-mod apt_repo;
-mod cmd_bootstraps;
-mod cmd_checkrepo;
-mod cmd_debinfo;
-mod cmd_notfound;
-mod cmd_package_apk;
-mod deb_file;
+/// Options selecting the apt repository to fetch packages from.
+#[derive(StructOpt, Debug)]
+struct RepoOpts {
+    /// A file with URL, Suite, Component and Signed-By fields to use as defaults
+    #[structopt(long)]
+    config: Option<String>,
+    /// An apt sources.list file, or a deb822 file ending in .sources, listing
+    /// the repositories to use in order of priority
+    #[structopt(long, conflicts_with_all = &["config", "repo-url", "suite", "components"])]
+    sources: Option<String>,
+    /// Base URL of the repository, using http://, https:// or file://
+    #[structopt(long)]
+    repo_url: Option<String>,
+    /// The suite (distribution) in the repository
+    #[structopt(long)]
+    suite: Option<String>,
+    /// A component in the suite. Can be repeated to use several components,
+    /// in order of priority
+    #[structopt(long = "component", number_of_values = 1)]
+    components: Vec<String>,
+    /// When a package is in several sources, use the highest version instead
+    /// of the one from the first source
+    #[structopt(long)]
+    highest_version: bool,
+    /// A keyring file with the keys trusted to sign the repository. With
+    /// --sources, only used for repositories without a signed-by option
+    #[structopt(long)]
+    keyring: Option<String>,
+    /// A directory to keep downloaded files in, reused across runs
+    #[structopt(long)]
+    cache_dir: Option<String>,
+    /// Only use files from the download cache, never downloading anything
+    #[structopt(long, requires = "cache-dir")]
+    offline: bool,
+    /// The maximum number of packages to download at the same time
+    #[structopt(long, default_value = "4")]
+    jobs: usize,
+    /// How many times to retry a failed download
+    #[structopt(long, default_value = "3")]
+    retries: u32,
+    /// The time limit in seconds for each download
+    #[structopt(long, default_value = "300")]
+    timeout: u64,
+    /// A proxy URL to use instead of the one from http_proxy and https_proxy
+    #[structopt(long)]
+    proxy: Option<String>,
+}
+
+impl RepoOpts {
+    fn priority(&self) -> SourcePriority {
+        if self.highest_version {
+            SourcePriority::HighestVersion
+        } else {
+            SourcePriority::FirstSource
+        }
+    }
+
+    fn into_sources(self) -> Vec<RepoSource> {
+        fn exit_on_error<T>(e: RepoError) -> T {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        let mut sources = match &self.sources {
+            Some(sources) => {
+                let sources =
+                    RepoSource::read_sources(Path::new(sources)).unwrap_or_else(exit_on_error);
+                if sources.is_empty() {
+                    eprintln!("No repositories listed in {}", self.sources.unwrap());
+                    std::process::exit(1);
+                }
+                sources
+            }
+            None => {
+                let mut source = match &self.config {
+                    Some(config) => {
+                        RepoSource::read_config(Path::new(config)).unwrap_or_else(exit_on_error)
+                    }
+                    None => RepoSource::default(),
+                };
+                if let Some(url) = self.repo_url {
+                    source.url = url;
+                }
+                if let Some(suite) = self.suite {
+                    source.suite = suite;
+                }
+                if let Some(keyring) = &self.keyring {
+                    source.signed_by = Some(keyring.into());
+                }
+                if self.components.is_empty() {
+                    vec![source]
+                } else {
+                    self.components
+                        .iter()
+                        .map(|component| RepoSource {
+                            component: component.clone(),
+                            ..source.clone()
+                        })
+                        .collect()
+                }
+            }
+        };
+
+        let download = DownloadOptions {
+            retries: self.retries,
+            timeout: Duration::from_secs(self.timeout),
+            proxy: self.proxy,
+            ..DownloadOptions::default()
+        };
+        let offline = self.offline;
+        let cache = self.cache_dir.map(|cache_dir| DownloadCache {
+            dir: cache_dir.into(),
+            offline,
+        });
+        for source in &mut sources {
+            if source.signed_by.is_none() {
+                source.signed_by = self.keyring.as_ref().map(PathBuf::from);
+            }
+            source.download = download.clone();
+            source.cache = cache.clone();
+        }
+        sources
+    }
+}
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "termux-packaging")]
@@ -30,20 +149,102 @@ enum Opt {
         version: u16,
         /// Output directory to create the zip files in
         directory: String,
+        /// A package to include with its dependencies, in addition to the
+        /// Essential packages. Can be repeated, replacing the default set
+        #[structopt(long = "package", number_of_values = 1)]
+        packages: Vec<String>,
+        #[structopt(flatten)]
+        repo: RepoOpts,
+    },
+    #[structopt(name = "build-deb")]
+    /// Build a deb file from a directory, like dpkg-deb --build
+    BuildDeb {
+        /// Compression to use
+        #[structopt(
+            short = "Z",
+            long,
+            default_value = "xz",
+            possible_values = &["none", "gzip", "xz", "bzip2", "zstd"]
+        )]
+        compression: Compression,
+        /// Directory containing DEBIAN/control and the files to package
+        directory: String,
+        /// The .deb file to create, or a directory to create it in
+        output: String,
     },
     #[structopt(name = "checkrepo")]
-    /// Check a local repository for problems
+    /// Check a local repository for problems, or check that the dependencies
+    /// of the packages in the configured repositories can be satisfied
     CheckRepo {
         /// Path to directory containing binary-* files
-        directory: String,
+        directory: Option<String>,
+        #[structopt(flatten)]
+        repo: RepoOpts,
     },
     #[structopt(name = "debinfo")]
     /// Show information about a deb file
     DebInfo {
+        /// Verify the package files against the md5sums control file
+        #[structopt(long)]
+        verify: bool,
+        /// List the files in the package, like dpkg -c
+        #[structopt(long)]
+        list: bool,
+        /// Show the conffiles of the package
+        #[structopt(long)]
+        conffiles: bool,
+        /// Show the maintainer scripts of the package
+        #[structopt(long)]
+        scripts: bool,
+        /// Output JSON, always including the control fields
+        #[structopt(long)]
+        json: bool,
         /// The .deb file to inspect
         #[structopt(name = "DEBFILE")]
         file: String,
     },
+    #[structopt(name = "extract")]
+    /// Extract the files of a deb file into a directory
+    Extract {
+        /// Also extract the control files into the DEBIAN subdirectory
+        #[structopt(short = "e", long = "control")]
+        control: bool,
+        /// The .deb file to extract
+        #[structopt(name = "DEBFILE")]
+        file: String,
+        /// The directory to extract into
+        directory: String,
+    },
+    #[structopt(name = "index")]
+    /// Generate Packages indexes for the deb files in a repository
+    Index {
+        /// Directory below the repository containing the deb files
+        #[structopt(long, default_value = "pool")]
+        pool: String,
+        /// The suite (distribution) to write indexes for
+        #[structopt(long, default_value = "stable")]
+        suite: String,
+        /// The component to write indexes for
+        #[structopt(long, default_value = "main")]
+        component: String,
+        /// The Origin field of the generated Release file
+        #[structopt(long)]
+        origin: Option<String>,
+        /// The Codename field of the generated Release file, defaulting to the suite
+        #[structopt(long)]
+        codename: Option<String>,
+        /// Number of days the Release file is valid, setting Valid-Until
+        #[structopt(long)]
+        valid_days: Option<u32>,
+        /// Do not publish by-hash copies of the indexes for Acquire-By-Hash
+        #[structopt(long)]
+        no_by_hash: bool,
+        /// A secret key file to sign the Release file with, creating InRelease and Release.gpg
+        #[structopt(long)]
+        signing_key: Option<String>,
+        /// The repository directory, where dists/ is created
+        directory: String,
+    },
     #[structopt(name = "notfound")]
     /// Update the command-not-found headers
     NotFound {
@@ -62,19 +263,104 @@ enum Opt {
         package: String,
         /// The directory where the generated project will be created
         output: String,
+        #[structopt(flatten)]
+        repo: RepoOpts,
     },
 }
 
 fn main() {
     match Opt::from_args() {
-        Opt::Bootstraps { directory, version } => cmd_bootstraps::create(&directory, version),
-        Opt::CheckRepo { directory } => cmd_checkrepo::check(&directory),
-        Opt::DebInfo { file } => cmd_debinfo::print(&file),
+        Opt::Bootstraps {
+            directory,
+            version,
+            packages,
+            repo,
+        } => {
+            let roots = if packages.is_empty() {
+                cmd_bootstraps::DEFAULT_ROOTS
+                    .iter()
+                    .map(|root| root.to_string())
+                    .collect()
+            } else {
+                packages
+            };
+            let (priority, jobs) = (repo.priority(), repo.jobs);
+            let sources = repo.into_sources();
+            cmd_bootstraps::create(&directory, version, &sources, priority, &roots, jobs)
+        }
+        Opt::BuildDeb {
+            compression,
+            directory,
+            output,
+        } => cmd_build_deb::build(&directory, &output, compression),
+        Opt::CheckRepo { directory, repo } => match directory {
+            Some(directory) => cmd_checkrepo::check(&directory),
+            None => {
+                let priority = repo.priority();
+                cmd_checkrepo::check_sources(&repo.into_sources(), priority)
+            }
+        },
+        Opt::DebInfo {
+            file,
+            verify,
+            list,
+            conffiles,
+            scripts,
+            json,
+        } => {
+            if verify {
+                cmd_debinfo::verify(&file, json)
+            } else {
+                let options = cmd_debinfo::PrintOptions {
+                    list,
+                    conffiles,
+                    scripts,
+                    json,
+                };
+                cmd_debinfo::print(&file, &options)
+            }
+        }
+        Opt::Extract {
+            control,
+            file,
+            directory,
+        } => cmd_extract::extract(&file, &directory, control),
+        Opt::Index {
+            pool,
+            suite,
+            component,
+            origin,
+            codename,
+            valid_days,
+            no_by_hash,
+            signing_key,
+            directory,
+        } => {
+            let release_options = ReleaseOptions {
+                origin,
+                suite,
+                codename,
+                valid_for: valid_days.map(|days| chrono::Duration::days(days.into())),
+                by_hash: !no_by_hash,
+            };
+            cmd_index::index(
+                &directory,
+                &pool,
+                &component,
+                &release_options,
+                signing_key.as_deref().map(Path::new),
+            )
+        }
         Opt::NotFound { repo, output } => cmd_notfound::update(repo, &output),
         Opt::PackageApk {
             install,
             package,
             output,
-        } => cmd_package_apk::create_apk(&package, &output, install),
+            repo,
+        } => {
+            let (priority, jobs) = (repo.priority(), repo.jobs);
+            let sources = repo.into_sources();
+            cmd_package_apk::create_apk(&package, &output, install, &sources, priority, jobs)
+        }
     }
 }
@@ -0,0 +1,369 @@
+//! Verification of OpenPGP signatures on repository Release files, against a
+//! keyring of trusted public keys like the ones apt uses for `signed-by`, and
+//! signing of Release files with a secret key.
+use pgp::composed::{
+    ArmorOptions, CleartextSignedMessage, Deserializable, DetachedSignature, SignedPublicKey,
+    SignedPublicSubKey, SignedSecretKey,
+};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::packet::{Signature, SignatureType, SubpacketData};
+use pgp::types::{KeyDetails, Password, Tag, Timestamp};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum SignatureError {
+    /// The keyring could not be read or contained no keys.
+    Keyring(String),
+    /// The signed message or signature could not be parsed.
+    Malformed(String),
+    /// No signature was made by a key in the keyring.
+    NotVerified,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::Keyring(message) => write!(f, "invalid keyring: {}", message),
+            SignatureError::Malformed(message) => write!(f, "malformed signature: {}", message),
+            SignatureError::NotVerified => write!(f, "no valid signature from a trusted key"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// A set of trusted public keys, read from an armored (`.asc`) or binary
+/// (`.gpg`) keyring file.
+pub struct Keyring {
+    keys: Vec<SignedPublicKey>,
+}
+
+/// If a key created at the given time has passed the expiration time set by
+/// its self-signature or binding signature.
+fn is_expired(created_at: Timestamp, signature: &Signature) -> bool {
+    signature.key_expiration_time().is_some_and(|expiration| {
+        expiration.as_secs() != 0
+            && u64::from(created_at.as_secs()) + u64::from(expiration.as_secs())
+                <= u64::from(Timestamp::now().as_secs())
+    })
+}
+
+/// If a self-signature allows its key to make signatures. Keys without key
+/// flags, as made by old gpg versions, may sign.
+fn allows_signing(signature: &Signature) -> bool {
+    let has_key_flags = signature.config().is_some_and(|config| {
+        config
+            .hashed_subpackets()
+            .any(|subpacket| matches!(subpacket.data, SubpacketData::KeyFlags(_)))
+    });
+    !has_key_flags || signature.key_flags().sign()
+}
+
+/// The latest self-signature of a primary key, if the key can be used like
+/// gpg requires before trusting it or its subkeys: it has a valid
+/// self-signature, is not revoked and has not expired.
+fn valid_self_signature(key: &SignedPublicKey) -> Option<&Signature> {
+    let primary_key = &key.primary_key;
+    let is_revoked = key.details.revocation_signatures.iter().any(|signature| {
+        signature.typ() == Some(SignatureType::KeyRevocation)
+            && signature.verify_key(primary_key).is_ok()
+    });
+    if is_revoked {
+        return None;
+    }
+    let certifications = key.details.users.iter().flat_map(|user| {
+        user.signatures.iter().filter(move |signature| {
+            signature
+                .verify_certification(primary_key, Tag::UserId, &user.id)
+                .is_ok()
+        })
+    });
+    let direct_signatures = key
+        .details
+        .direct_signatures
+        .iter()
+        .filter(|signature| signature.verify_key(primary_key).is_ok());
+    let self_signature = certifications
+        .chain(direct_signatures)
+        .max_by_key(|signature| signature.created().map(Timestamp::as_secs))?;
+    if is_expired(primary_key.created_at(), self_signature) {
+        return None;
+    }
+    Some(self_signature)
+}
+
+/// If a subkey is bound to its primary key for signing, like gpg requires:
+/// by a binding signature from the primary key with the signing flag, which
+/// embeds a signature from the subkey binding it back to the primary key,
+/// and the subkey is neither revoked nor expired.
+fn is_signing_subkey(key: &SignedPublicKey, subkey: &SignedPublicSubKey) -> bool {
+    let is_valid = |signature: &Signature| {
+        signature
+            .verify_subkey_binding(&key.primary_key, &subkey.key)
+            .is_ok()
+    };
+    let is_revoked = subkey.signatures.iter().any(|signature| {
+        signature.typ() == Some(SignatureType::SubkeyRevocation) && is_valid(signature)
+    });
+    let is_bound = subkey.signatures.iter().any(|signature| {
+        signature.typ() == Some(SignatureType::SubkeyBinding)
+            && signature.key_flags().sign()
+            && is_valid(signature)
+            && !is_expired(subkey.key.created_at(), signature)
+            && signature
+                .embedded_signature()
+                .is_some_and(|back_signature| {
+                    back_signature
+                        .verify_primary_key_binding(&subkey.key, &key.primary_key)
+                        .is_ok()
+                })
+    });
+    is_bound && !is_revoked
+}
+
+impl Keyring {
+    pub fn read(path: &Path) -> Result<Keyring, SignatureError> {
+        let mut contents = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(|e| SignatureError::Keyring(format!("{}: {}", path.display(), e)))?;
+        Keyring::from_bytes(&contents)
+    }
+
+    pub fn from_bytes(contents: &[u8]) -> Result<Keyring, SignatureError> {
+        let keyring_error = |e: pgp::errors::Error| SignatureError::Keyring(e.to_string());
+        let (keys, _) = SignedPublicKey::from_reader_many(contents).map_err(keyring_error)?;
+        let keys = keys.collect::<Result<Vec<_>, _>>().map_err(keyring_error)?;
+        if keys.is_empty() {
+            return Err(SignatureError::Keyring("no public keys found".to_string()));
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// If a signature over data was made by a valid primary key allowed to
+    /// sign, or one of its signing subkeys, in the keyring.
+    fn is_trusted(&self, signature: &Signature, data: &[u8]) -> bool {
+        self.keys.iter().any(|key| {
+            let self_signature = match valid_self_signature(key) {
+                Some(self_signature) => self_signature,
+                None => return false,
+            };
+            (allows_signing(self_signature) && signature.verify(key, data).is_ok())
+                || key.public_subkeys.iter().any(|subkey| {
+                    is_signing_subkey(key, subkey) && signature.verify(&subkey.key, data).is_ok()
+                })
+        })
+    }
+
+    /// Verify a clearsigned message such as InRelease, returning the signed
+    /// text with dash-escaping removed and `\n` line endings.
+    pub fn verify_clearsigned(&self, message: &str) -> Result<String, SignatureError> {
+        let (message, _) = CleartextSignedMessage::from_string(message)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))?;
+        let signed_text = message.signed_text();
+        let verified = message
+            .signatures()
+            .iter()
+            .any(|signature| self.is_trusted(signature, signed_text.as_bytes()));
+        if !verified {
+            return Err(SignatureError::NotVerified);
+        }
+        let mut text = signed_text.replace("\r\n", "\n");
+        text.push('\n');
+        Ok(text)
+    }
+
+    /// Verify a detached signature, such as Release.gpg for a Release file.
+    pub fn verify_detached(&self, data: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+        let (signatures, _) = DetachedSignature::from_reader_many(signature)
+            .map_err(|e| SignatureError::Malformed(e.to_string()))?;
+        for signature in signatures {
+            let signature = signature.map_err(|e| SignatureError::Malformed(e.to_string()))?;
+            if self.is_trusted(&signature.signature, data) {
+                return Ok(());
+            }
+        }
+        Err(SignatureError::NotVerified)
+    }
+}
+
+/// A secret key for signing Release files, read from an armored or binary
+/// key file. Keys protected by a passphrase are not supported.
+pub struct SigningKey {
+    key: SignedSecretKey,
+}
+
+impl SigningKey {
+    pub fn read(path: &Path) -> Result<SigningKey, SignatureError> {
+        let mut contents = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(|e| SignatureError::Keyring(format!("{}: {}", path.display(), e)))?;
+        SigningKey::from_bytes(&contents)
+    }
+
+    pub fn from_bytes(contents: &[u8]) -> Result<SigningKey, SignatureError> {
+        let (key, _) = SignedSecretKey::from_reader_single(contents)
+            .map_err(|e| SignatureError::Keyring(e.to_string()))?;
+        Ok(SigningKey { key })
+    }
+
+    /// Create a clearsigned message, such as InRelease from a Release file.
+    pub fn sign_clearsigned(&self, text: &str) -> Result<String, SignatureError> {
+        // Like gpg, do not sign the line break before the signature.
+        CleartextSignedMessage::sign(
+            rand::thread_rng(),
+            text.strip_suffix('\n').unwrap_or(text),
+            &self.key.primary_key,
+            &Password::default(),
+        )
+        .and_then(|message| message.to_armored_string(ArmorOptions::default()))
+        .map_err(|e| SignatureError::Malformed(e.to_string()))
+    }
+
+    /// Create an armored detached signature, such as Release.gpg.
+    pub fn sign_detached(&self, data: &[u8]) -> Result<String, SignatureError> {
+        DetachedSignature::sign_binary_data(
+            rand::thread_rng(),
+            &self.key.primary_key,
+            &Password::default(),
+            HashAlgorithm::Sha256,
+            data,
+        )
+        .and_then(|signature| signature.to_armored_string(ArmorOptions::default()))
+        .map_err(|e| SignatureError::Malformed(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RELEASE: &str = include_str!("testdata/Release");
+    const IN_RELEASE: &str = include_str!("testdata/InRelease");
+
+    fn keyring() -> Keyring {
+        Keyring::from_bytes(include_bytes!("testdata/keyring.asc")).unwrap()
+    }
+
+    fn other_keyring() -> Keyring {
+        Keyring::from_bytes(include_bytes!("testdata/other.gpg")).unwrap()
+    }
+
+    #[test]
+    fn test_verify_clearsigned() {
+        assert_eq!(RELEASE, keyring().verify_clearsigned(IN_RELEASE).unwrap());
+        assert!(matches!(
+            other_keyring().verify_clearsigned(IN_RELEASE),
+            Err(SignatureError::NotVerified)
+        ));
+
+        let tampered = IN_RELEASE.replace("Suite: stable", "Suite: stabl3");
+        assert!(matches!(
+            keyring().verify_clearsigned(&tampered),
+            Err(SignatureError::NotVerified)
+        ));
+        assert!(matches!(
+            keyring().verify_clearsigned(RELEASE),
+            Err(SignatureError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_detached() {
+        let signature = include_bytes!("testdata/Release.gpg");
+        assert!(keyring()
+            .verify_detached(RELEASE.as_bytes(), signature)
+            .is_ok());
+        assert!(matches!(
+            other_keyring().verify_detached(RELEASE.as_bytes(), signature),
+            Err(SignatureError::NotVerified)
+        ));
+        let tampered = RELEASE.replace("Suite: stable", "Suite: stabl3");
+        assert!(matches!(
+            keyring().verify_detached(tampered.as_bytes(), signature),
+            Err(SignatureError::NotVerified)
+        ));
+    }
+
+    #[test]
+    fn test_verify_subkey_signature() {
+        let signature = include_bytes!("testdata/Release.subkey.gpg");
+        let subkey_keyring = || Keyring::from_bytes(include_bytes!("testdata/subkey.asc")).unwrap();
+        assert!(subkey_keyring()
+            .verify_detached(RELEASE.as_bytes(), signature)
+            .is_ok());
+
+        // A subkey without a binding signature from its primary key.
+        let mut unbound = subkey_keyring();
+        unbound.keys[0].public_subkeys[0].signatures.clear();
+        assert!(matches!(
+            unbound.verify_detached(RELEASE.as_bytes(), signature),
+            Err(SignatureError::NotVerified)
+        ));
+
+        // A subkey attached to another primary key, with the binding signature
+        // of the original one.
+        let mut moved = other_keyring();
+        moved.keys[0]
+            .public_subkeys
+            .push(subkey_keyring().keys[0].public_subkeys[0].clone());
+        assert!(matches!(
+            moved.verify_detached(RELEASE.as_bytes(), signature),
+            Err(SignatureError::NotVerified)
+        ));
+    }
+
+    #[test]
+    fn test_verify_revoked_key_signature() {
+        let signature = include_bytes!("testdata/Release.revoked.gpg");
+        let revoked_keyring =
+            || Keyring::from_bytes(include_bytes!("testdata/revoked.asc")).unwrap();
+        assert!(matches!(
+            revoked_keyring().verify_detached(RELEASE.as_bytes(), signature),
+            Err(SignatureError::NotVerified)
+        ));
+
+        // The signature was made before the key was revoked.
+        let mut unrevoked = revoked_keyring();
+        unrevoked.keys[0].details.revocation_signatures.clear();
+        assert!(unrevoked
+            .verify_detached(RELEASE.as_bytes(), signature)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_expired_key_signature() {
+        // Made by a key which expired a day after it was created in 2020.
+        let signature = include_bytes!("testdata/Release.expired.gpg");
+        let expired_keyring = Keyring::from_bytes(include_bytes!("testdata/expired.asc")).unwrap();
+        assert!(matches!(
+            expired_keyring.verify_detached(RELEASE.as_bytes(), signature),
+            Err(SignatureError::NotVerified)
+        ));
+    }
+
+    #[test]
+    fn test_sign() {
+        let signing_key = SigningKey::from_bytes(include_bytes!("testdata/secret.asc")).unwrap();
+        let in_release = signing_key.sign_clearsigned(RELEASE).unwrap();
+        assert_eq!(RELEASE, keyring().verify_clearsigned(&in_release).unwrap());
+        assert!(other_keyring().verify_clearsigned(&in_release).is_err());
+
+        let signature = signing_key.sign_detached(RELEASE.as_bytes()).unwrap();
+        assert!(keyring()
+            .verify_detached(RELEASE.as_bytes(), signature.as_bytes())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_invalid_keyring() {
+        assert!(matches!(
+            Keyring::from_bytes(b"not a key"),
+            Err(SignatureError::Keyring(_))
+        ));
+    }
+}